@@ -4,7 +4,9 @@
 # Arg 2: Input dir (kll files)
 # Arg 2: Output file
 # Env: DefaultMapOverride, PartialMapsExpandedOverride, Layout
-#      SPLIT_KEYBOARD
+#      SPLIT_KEYBOARD, SPLIT_KEYBOARD_LEFT, SPLIT_KEYBOARD_RIGHT (per-half kll dirs, relative to IN_DIR)
+#      BUILD_DIR (optional, defaults to a new temporary directory)
+#      KLL_CACHE, GITHUB_APIKEY_FILE (optional, skipped when missing)
 #
 # Example:
 # export DefaultMapOverride="stdFuncMap KType-Standard-0"
@@ -34,8 +36,8 @@ if [ "$#" -lt 3 ]; then
 fi
 
 # Double check with docker volume mountpoints
-CONTROLLER_DIR="/controller"
-BUILD_DIR="$(mktemp -d)"
+CONTROLLER_DIR="${CONTROLLER_DIR:-/controller}"
+BUILD_DIR="${BUILD_DIR:-$(mktemp -d)}"
 IN_DIR="${IN_DIR:-/mnt/config}"
 OUT_DIR="${OUT_DIR:-/mnt/builds}"
 
@@ -55,26 +57,34 @@ echo "------------------------"
 export PATH="/usr/lib/ccache:$PATH"
 
 # try to use a cached kll layouts dir
-for f in /kll_cache/*; do ln -s "$f" /tmp/; done
+KLL_CACHE="${KLL_CACHE:-/kll_cache}"
+if [ -d "${KLL_CACHE}" ]; then
+	for f in "${KLL_CACHE}"/*; do ln -s "$f" /tmp/; done
+fi
 
 # try to use a github apikey secret
-[ -z "$GITHUB_APIKEY" ] && export GITHUB_APIKEY="$(cat /run/secrets/github_apikey)"
+GITHUB_APIKEY_FILE="${GITHUB_APIKEY_FILE:-/run/secrets/github_apikey}"
+if [ -z "$GITHUB_APIKEY" ] && [ -f "${GITHUB_APIKEY_FILE}" ]; then
+	export GITHUB_APIKEY="$(cat "${GITHUB_APIKEY_FILE}")"
+fi
 
 #set -x
+# each half of a split keyboard is built from its own set of kll files
+if [ "${SPLIT_KEYBOARD}" == "1" ]; then
+	LBuildPath="${BUILD_DIR}/left"
+	RBuildPath="${BUILD_DIR}/right"
+	mv "${IN_DIR}/${SPLIT_KEYBOARD_LEFT}" "${LBuildPath}"
+	mv "${IN_DIR}/${SPLIT_KEYBOARD_RIGHT}" "${RBuildPath}"
+fi
+
 # the kll compiler looks for files in the build dir
 mv ${KllDir}/* "${BUILD_DIR}"
 rmdir "${KllDir}/${HASH}"
 
 if [ "${SPLIT_KEYBOARD}" == "1" ]; then
-	LBuildPath="${BUILD_DIR}/left"
-	mkdir -p "${LBuildPath}"
-	cp ${BUILD_DIR}/*.kll "${LBuildPath}"
 	build "${BuildScript%.*}-l.bash" "${LBuildPath}" &
 	PID_LEFT=$!
 
-	RBuildPath="${BUILD_DIR}/right"
-	mkdir -p "${RBuildPath}"
-	cp ${BUILD_DIR}/*.kll "${RBuildPath}"
 	build "${BuildScript%.*}-r.bash" "${RBuildPath}" &
 	PID_RIGHT=$!
 
@@ -1,10 +1,17 @@
-use crate::kll::KllConfig;
-
+use crate::dialect::{container_dialect, DIALECTS};
+use crate::keyboard::{keyboard, Keyboard};
 use crate::kll::*;
+use sha2::{Digest, Sha256};
 use shared_child::SharedChild;
-use std::process::Command;
-use std::path::Path;
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct BuildInfo {
@@ -27,40 +34,18 @@ pub fn configure_build(config: &KllConfig, layers: Vec<String>) -> BuildInfo {
         .replace(" ", "_");
     let layout = config.header.layout.clone().replace(" ", "_");
 
-    let build_script = match name.to_lowercase().as_ref() {
-        "md1" => "infinity.bash",
-        "md1.1" => "infinity_led.bash",
-        "infinity" => "infinity.bash",
-        "icpad" => "icpad.bash",
-        "mdergo1" => "ergodox.bash",
-        "ergodox" => "ergodox.bash",
-        "whitefox" => "whitefox.bash",
-        "ktype" => "k-type.bash",
-        "k-type" => "k-type.bash",
-        "kira" => "kira.bash",
-        "gemini" => "geminiduskdawn.bash",
-        "geminidusk" => "geminiduskdawn.bash",
-        "geminidawn" => "geminiduskdawn.bash",
-        "geminiduskdawn" => "geminiduskdawn.bash",
-        _ => panic!("Unknown keyboard {}", name),
-    }
-    .to_string();
-
-    let split_keyboard = match name.to_lowercase().as_ref() {
-        "mdergo1" => true,
-        _ => false,
-    };
-
-    let extra_map = match name.to_lowercase().as_ref() {
-        "mdergo1" => vec![
-            "infinity_ergodox/lcdFuncMap".to_string(),
-        ],
-        _ => vec!["stdFuncMap".to_string()],
-    };
+    // Unknown keyboards are rejected by validate_keyboard before building
+    let keyboard = keyboard(&name).unwrap_or_else(|| panic!("Unknown keyboard {}", name));
+    let build_script = keyboard.build_script.clone();
+    let split_keyboard = keyboard.split;
+    let extra_map = keyboard.extra_maps.clone();
 
     let mut layers = layers.iter();
     let base_layer_kll = kll_filename(layers.next().unwrap().to_string());
-    let base_layer = Path::new(&base_layer_kll).file_stem().unwrap_or(OsStr::new("")).to_os_string();
+    let base_layer = Path::new(&base_layer_kll)
+        .file_stem()
+        .unwrap_or(OsStr::new(""))
+        .to_os_string();
 
     let default_map = {
         // TODO (HaaTa): extra_map is likely not necessary anymore
@@ -73,7 +58,10 @@ pub fn configure_build(config: &KllConfig, layers: Vec<String>) -> BuildInfo {
         .map(|l| {
             //let mut layer = extra_map.clone();
             let mut layer = vec![];
-            let partial_layer = Path::new(&l).file_stem().unwrap_or(OsStr::new("")).to_os_string();
+            let partial_layer = Path::new(&l)
+                .file_stem()
+                .unwrap_or(OsStr::new(""))
+                .to_os_string();
             layer.push(partial_layer.into_string().unwrap());
             kll_layer(layer)
         })
@@ -90,92 +78,544 @@ pub fn configure_build(config: &KllConfig, layers: Vec<String>) -> BuildInfo {
     }
 }
 
-pub fn start_build(
-    container: String,
-    config: BuildInfo,
-    kll_dir: String,
-    output_file: String,
-) -> SharedChild {
-    let mut args = vec![
-        "run".to_string(),
-        "--rm".to_string(),
-        "-T".to_string(),
-        "-e".to_string(),
-        format!("DefaultMapOverride={}", kll_layer(config.default_map)),
-        "-e".to_string(),
-        format!(
-            "PartialMapsExpandedOverride={}",
-            kll_list(config.partial_maps)
+/// Hex characters of the digest used as the build hash in job ids and file names
+pub const BUILD_HASH_LEN: usize = 16;
+
+/// SHA-256 over everything that determines the firmware: the configuration, the container
+/// and controller git tag it is built with, the keyboard's build settings, the base layouts
+/// the kll files are generated against and the generator version. Returned as lowercase hex,
+/// fails if a base layout can't be read.
+pub fn build_digest(
+    config: &KllConfig,
+    container: &str,
+    git_tag: &str,
+    minimal: bool,
+) -> Result<String, String> {
+    // Re-serializing the parsed config drops formatting, unknown fields and the order of
+    //  struct fields. Map order is kept, it decides the order of animations in the kll files.
+    let canonical = serde_json::to_vec(config).expect("Could not serialize config");
+    let (base_file, target_file) = base_layout_files(config, container_dialect(container));
+
+    let mut hasher = Sha256::new();
+    let mut field = |name: &str, value: &[u8]| {
+        // Length prefixed so fields can't run into each other
+        hasher.update(format!("{}:{}:", name, value.len()).as_bytes());
+        hasher.update(value);
+    };
+    field("generator", GENERATOR_VERSION.to_string().as_bytes());
+    field("container", container.as_bytes());
+    field("git_tag", git_tag.as_bytes());
+    field("minimal", &[minimal as u8]);
+    field("config", &canonical);
+    let keyboard = keyboard(&config.header.name).map(Keyboard::build_inputs);
+    let keyboard = serde_json::to_vec(&keyboard).unwrap();
+    field("keyboard", &keyboard);
+    for file in [base_file, target_file].iter() {
+        let contents = fs::read(file).map_err(|_| format!("Missing layout {}", file))?;
+        field("base", &contents);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A running (or already finished) firmware build
+pub trait BuildJob: Send + Sync {
+    fn id(&self) -> u32;
+    /// Blocks until the build exits, returns whether it succeeded
+    fn wait(&self) -> io::Result<bool>;
+    fn kill(&self) -> io::Result<()>;
+}
+
+impl BuildJob for SharedChild {
+    fn id(&self) -> u32 {
+        SharedChild::id(self)
+    }
+
+    fn wait(&self) -> io::Result<bool> {
+        SharedChild::wait(self).map(|status| status.success())
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        SharedChild::kill(self)
+    }
+}
+
+/// A build process that leaves something else running when killed on its own,
+/// `stop` is run first to clean that up.
+pub struct BuildProcess {
+    pub process: SharedChild,
+    pub stop: Vec<String>,
+}
+
+impl BuildJob for BuildProcess {
+    fn id(&self) -> u32 {
+        self.process.id()
+    }
+
+    fn wait(&self) -> io::Result<bool> {
+        BuildJob::wait(&self.process)
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        if let Some((program, args)) = self.stop.split_first() {
+            if let Err(e) = Command::new(program).args(args).status() {
+                println!("Error: Failed to run {}: {}", program, e);
+            }
+        }
+        self.process.kill()
+    }
+}
+
+/// A stub build that finishes once its delay has passed, or fails as soon as it is killed
+pub struct StubJob {
+    success: bool,
+    deadline: Instant,
+    killed: Mutex<bool>,
+    stopped: Condvar,
+}
+
+impl StubJob {
+    pub fn new(success: bool, delay: Duration) -> StubJob {
+        StubJob {
+            success,
+            deadline: Instant::now() + delay,
+            killed: Mutex::new(false),
+            stopped: Condvar::new(),
+        }
+    }
+}
+
+impl BuildJob for StubJob {
+    fn id(&self) -> u32 {
+        0
+    }
+
+    fn wait(&self) -> io::Result<bool> {
+        let mut killed = self.killed.lock().expect("Could not lock mutex");
+        loop {
+            let now = Instant::now();
+            if *killed {
+                return Ok(false);
+            }
+            if now >= self.deadline {
+                return Ok(self.success);
+            }
+            killed = self
+                .stopped
+                .wait_timeout(killed, self.deadline - now)
+                .expect("Could not lock mutex")
+                .0;
+        }
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        *self.killed.lock().expect("Could not lock mutex") = true;
+        self.stopped.notify_all();
+        Ok(())
+    }
+}
+
+/// Runs the controller build scripts somewhere.
+/// `kll_dir` is relative to the config directory and `output_file` to the build directory.
+/// All build output is written to `log`.
+pub trait BuildBackend: Send + Sync {
+    fn spawn(
+        &self,
+        container: &str,
+        config: BuildInfo,
+        kll_dir: &str,
+        output_file: &str,
+        log: &Path,
+    ) -> io::Result<Arc<dyn BuildJob>>;
+
+    /// Names of the build environments (firmware versions) that can be built with
+    fn environments(&self) -> Vec<String>;
+
+    /// Artifacts that have been built in an environment
+    fn artifacts(&self, environment: &str) -> Vec<String>;
+
+    /// Called once the build's artifact has been packaged, to remove what the build left behind
+    fn clean_up(&self, _kll_dir: &str) {}
+}
+
+/// Environment variables understood by build.sh
+fn build_env(config: &BuildInfo, kll_dir: &str) -> Vec<(String, String)> {
+    let mut env = vec![
+        (
+            "DefaultMapOverride".to_string(),
+            kll_layer(config.default_map.clone()),
+        ),
+        (
+            "PartialMapsExpandedOverride".to_string(),
+            kll_list(config.partial_maps.clone()),
         ),
-        "-e".to_string(),
-        format!("Layout={}", config.variant),
+        ("Layout".to_string(), config.variant.clone()),
     ];
 
     if config.split_keyboard {
-        args.push("-e".to_string());
-        args.push("SPLIT_KEYBOARD=1".to_string());
-    }
-
-    args.extend_from_slice(&[container.clone(), config.build_script, kll_dir, output_file]);
-
-    let mut compile = Command::new("docker-compose");
-    compile.args(&args);
-    let process = SharedChild::spawn(&mut compile).expect("docker-compose failed to run container");
-
-    println!(" >> Created PID: {} ({})", process.id(), container);
-    return process;
-}
-
-pub fn list_containers() -> Vec<String> {
-    let result = Command::new("docker-compose")
-        .args(&["config", "--services"])
-        .output()
-        .expect("Please install docker-compose");
-    let out = String::from_utf8_lossy(&result.stdout);
-    out.lines()
-        .filter(|s| !s.contains("template"))
-        .map(|s| s.to_string())
-        .collect()
-}
-
-pub fn get_builds(service: &str) -> String {
-    let result = Command::new("docker-compose")
-        .args(&[
-            "run",
-            "--rm",
-            "--entrypoint",
-            "/usr/bin/find",
-            service,
-            "/mnt/builds",
-            "-printf",
-            "%P\n",
-        ])
-        .output()
-        .expect("docker-compose failed!");
-    String::from_utf8_lossy(&result.stdout).to_string()
-}
-
-fn old_builds(service: &str) {
-    let status = Command::new("docker-compose")
-        .args(&[
-            "run",
-            "--rm",
-            "--entrypoint",
-            "/usr/bin/find",
-            service,
-            "/mnt/builds",
-            //"-mtime", "+1",
-            "-depth",
-            "-mmin",
-            "+5",
-            "-print",
-        ])
-        .status()
-        .expect("docker-compose failed!");
-
-    if status.success() {
-        println!("Success!");
-    } else {
-        println!("Failed.");
+        env.push(("SPLIT_KEYBOARD".to_string(), "1".to_string()));
+        for half in SPLIT_HALVES.iter() {
+            let dir = format!("{}/{}", kll_dir, half);
+            env.push((format!("SPLIT_KEYBOARD_{}", half.to_uppercase()), dir));
+        }
+    }
+
+    env
+}
+
+/// Sends both stdout and stderr of the build to the log file
+fn capture_output(command: &mut Command, log: &Path) -> io::Result<()> {
+    let file = File::create(log)?;
+    command
+        .stdout(Stdio::from(file.try_clone()?))
+        .stderr(Stdio::from(file));
+    Ok(())
+}
+
+/// build.sh run on the host against `controller_dir`, reading the kll files from `in_dir` and
+/// writing the zip to `out_dir`
+fn build_script(
+    controller_dir: &Path,
+    in_dir: &Path,
+    out_dir: &Path,
+    config: &BuildInfo,
+    kll_dir: &str,
+    output_file: &str,
+) -> io::Result<Command> {
+    let mut compile = Command::new(fs::canonicalize("build.sh")?);
+    compile
+        .current_dir(controller_dir.join("Keyboards"))
+        .env("CONTROLLER_DIR", controller_dir)
+        .env("IN_DIR", fs::canonicalize(in_dir)?)
+        .env("OUT_DIR", fs::canonicalize(out_dir)?)
+        .envs(build_env(config, kll_dir))
+        .args([&config.build_script, kll_dir, output_file]);
+    Ok(compile)
+}
+
+/// Starts a build on the host in its own process group, so the whole compiler pipeline
+/// can be killed
+fn spawn_process_group(
+    compile: &mut Command,
+    log: &Path,
+    description: &str,
+) -> io::Result<Arc<dyn BuildJob>> {
+    compile.process_group(0);
+    capture_output(compile, log)?;
+    let process = SharedChild::spawn(compile)?;
+
+    println!(" >> Created PID: {} ({})", process.id(), description);
+    let group = format!("-{}", process.id());
+    Ok(Arc::new(BuildProcess {
+        process,
+        stop: vec![
+            "kill".to_string(),
+            "-KILL".to_string(),
+            "--".to_string(),
+            group,
+        ],
+    }))
+}
+
+/// Builds inside of the docker-compose `controller-XXX` services
+pub struct DockerComposeBackend;
+
+impl BuildBackend for DockerComposeBackend {
+    fn spawn(
+        &self,
+        container: &str,
+        config: BuildInfo,
+        kll_dir: &str,
+        output_file: &str,
+        log: &Path,
+    ) -> io::Result<Arc<dyn BuildJob>> {
+        // Named so the container can be stopped, killing docker-compose leaves it running
+        let name = format!("kiisrv-{}", kll_dir);
+        let mut args = vec!["run".to_string(), "--rm".to_string(), "-T".to_string()];
+        args.extend_from_slice(&["--name".to_string(), name.clone()]);
+        for (k, v) in build_env(&config, kll_dir) {
+            args.push("-e".to_string());
+            args.push(format!("{}={}", k, v));
+        }
+
+        args.extend_from_slice(&[
+            container.to_string(),
+            config.build_script,
+            kll_dir.to_string(),
+            output_file.to_string(),
+        ]);
+
+        let mut compile = Command::new("docker-compose");
+        compile.args(&args);
+        capture_output(&mut compile, log)?;
+        let process = SharedChild::spawn(&mut compile)?;
+
+        println!(" >> Created PID: {} ({})", process.id(), container);
+        Ok(Arc::new(BuildProcess {
+            process,
+            stop: vec!["docker".to_string(), "kill".to_string(), name],
+        }))
+    }
+
+    fn environments(&self) -> Vec<String> {
+        let result = Command::new("docker-compose")
+            .args(&["config", "--services"])
+            .output()
+            .expect("Please install docker-compose");
+        let out = String::from_utf8_lossy(&result.stdout);
+        out.lines()
+            .filter(|s| !s.contains("template"))
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    fn artifacts(&self, environment: &str) -> Vec<String> {
+        let result = Command::new("docker-compose")
+            .args(&[
+                "run",
+                "--rm",
+                "--entrypoint",
+                "/usr/bin/find",
+                environment,
+                "/mnt/builds",
+                "-printf",
+                "%P\n",
+            ])
+            .output()
+            .expect("docker-compose failed!");
+        String::from_utf8_lossy(&result.stdout)
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect()
+    }
+}
+
+fn list_files(dir: &Path) -> Vec<String> {
+    let mut files = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    files
+}
+
+/// Runs build.sh directly against controller checkouts, one per environment
+/// (`<root>/controller-057`, ...). Requires the firmware toolchain on the host.
+pub struct LocalBackend {
+    pub root: PathBuf,
+    pub config_dir: PathBuf,
+    pub build_dir: PathBuf,
+}
+
+impl BuildBackend for LocalBackend {
+    fn spawn(
+        &self,
+        container: &str,
+        config: BuildInfo,
+        kll_dir: &str,
+        output_file: &str,
+        log: &Path,
+    ) -> io::Result<Arc<dyn BuildJob>> {
+        let controller_dir = fs::canonicalize(self.root.join(container))?;
+        let mut compile = build_script(
+            &controller_dir,
+            &self.config_dir,
+            &self.build_dir,
+            &config,
+            kll_dir,
+            output_file,
+        )?;
+        spawn_process_group(&mut compile, log, container)
+    }
+
+    fn environments(&self) -> Vec<String> {
+        list_files(&self.root)
+            .into_iter()
+            .filter(|name| self.root.join(name).is_dir())
+            .collect()
+    }
+
+    fn artifacts(&self, _environment: &str) -> Vec<String> {
+        list_files(&self.build_dir)
+    }
+}
+
+/// Runs build.sh without a container, from a checkout of the release tag of each environment.
+/// Checkouts are git worktrees of `repo`, made on first use in `<work_dir>/checkouts/<tag>`.
+/// Each build gets its own working directory, `<work_dir>/builds/<hash>`, which is removed once
+/// the artifact is packaged (or, for stopped builds, when the same configuration is built
+/// again). Requires the firmware toolchain on the host.
+pub struct NativeBackend {
+    /// Clone of the controller repository, with the release tags fetched
+    pub repo: PathBuf,
+    /// Release tag of each environment, `controller-057` -> `v0.5.7`
+    pub tags: HashMap<String, String>,
+    pub work_dir: PathBuf,
+    pub config_dir: PathBuf,
+    pub build_dir: PathBuf,
+    /// Held while a tag is being checked out, so concurrent builds of a new release share one
+    pub checkouts: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl NativeBackend {
+    /// Checkout of a release tag, created when it doesn't exist yet
+    pub fn checkout(&self, tag: &str) -> io::Result<PathBuf> {
+        let lock = {
+            let mut checkouts = self.checkouts.lock().expect("Could not lock mutex");
+            checkouts.entry(tag.to_string()).or_default().clone()
+        };
+        let _checkout = lock.lock().expect("Could not lock mutex");
+
+        let dir = self.work_dir.join("checkouts").join(tag);
+        if !dir.join("Keyboards").is_dir() {
+            fs::create_dir_all(self.work_dir.join("checkouts"))?;
+            println!(" >> Checking out {} into {:?}", tag, dir);
+            let out = Command::new("git")
+                .arg("-C")
+                .arg(&self.repo)
+                .args(["worktree", "add", "--force", "--detach"])
+                .arg(fs::canonicalize(self.work_dir.join("checkouts"))?.join(tag))
+                .arg(tag)
+                .output()?;
+            if !out.status.success() {
+                return Err(io::Error::other(format!(
+                    "Could not check out {} from {:?}: {}",
+                    tag,
+                    self.repo,
+                    String::from_utf8_lossy(&out.stderr).trim()
+                )));
+            }
+        }
+        fs::canonicalize(dir)
+    }
+
+    /// Tags that exist in the repository
+    fn repo_tags(&self) -> Vec<String> {
+        let result = Command::new("git")
+            .arg("-C")
+            .arg(&self.repo)
+            .args(["tag", "--list"])
+            .output();
+        match result {
+            Ok(out) => String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .map(|l| l.trim().to_string())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl BuildBackend for NativeBackend {
+    fn spawn(
+        &self,
+        container: &str,
+        config: BuildInfo,
+        kll_dir: &str,
+        output_file: &str,
+        log: &Path,
+    ) -> io::Result<Arc<dyn BuildJob>> {
+        let tag = self.tags.get(container).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No release tag for {}", container),
+            )
+        })?;
+        let controller_dir = self.checkout(tag)?;
+
+        // Left over from an earlier build of the same configuration
+        let work = self.work_dir.join("builds").join(kll_dir);
+        if work.exists() {
+            fs::remove_dir_all(&work)?;
+        }
+        fs::create_dir_all(&work)?;
+
+        let mut compile = build_script(
+            &controller_dir,
+            &self.config_dir,
+            &self.build_dir,
+            &config,
+            kll_dir,
+            output_file,
+        )?;
+        compile.env("BUILD_DIR", fs::canonicalize(&work)?);
+        spawn_process_group(&mut compile, log, &format!("{} at {}", container, tag))
+    }
+
+    fn environments(&self) -> Vec<String> {
+        let available = self.repo_tags();
+        let mut environments: Vec<String> = self
+            .tags
+            .iter()
+            .filter(|(_, tag)| available.contains(tag))
+            .map(|(container, _)| container.clone())
+            .collect();
+        environments.sort();
+        environments
+    }
+
+    fn artifacts(&self, _environment: &str) -> Vec<String> {
+        list_files(&self.build_dir)
+    }
+
+    fn clean_up(&self, kll_dir: &str) {
+        let work = self.work_dir.join("builds").join(kll_dir);
+        if let Err(e) = fs::remove_dir_all(&work) {
+            println!("Error: Failed to remove {:?}: {}", work, e);
+        }
+    }
+}
+
+/// Smallest valid zip file, just the end of central directory record
+const EMPTY_ZIP: [u8; 22] = [
+    0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Doesn't build anything, writes an empty zip in place of the firmware.
+/// Useful for exercising the rest of the server without a toolchain.
+pub struct StubBackend {
+    pub build_dir: PathBuf,
+    pub success: bool,
+    /// How long each build appears to run
+    pub delay: Duration,
+}
+
+impl BuildBackend for StubBackend {
+    fn spawn(
+        &self,
+        container: &str,
+        _config: BuildInfo,
+        _kll_dir: &str,
+        output_file: &str,
+        log: &Path,
+    ) -> io::Result<Arc<dyn BuildJob>> {
+        let output_file = match self.success {
+            true => output_file.to_string(),
+            false => output_file.replace(".zip", "_error.zip"),
+        };
+        fs::create_dir_all(&self.build_dir)?;
+        fs::write(self.build_dir.join(&output_file), &EMPTY_ZIP[..])?;
+        fs::write(
+            log,
+            format!("Stub build of {} in {}\n", output_file, container),
+        )?;
+
+        println!(" >> Stub build {} ({})", output_file, container);
+        Ok(Arc::new(StubJob::new(self.success, self.delay)))
+    }
+
+    fn environments(&self) -> Vec<String> {
+        DIALECTS
+            .iter()
+            .flat_map(|d| d.containers.iter().map(|c| c.to_string()))
+            .collect()
+    }
+
+    fn artifacts(&self, _environment: &str) -> Vec<String> {
+        list_files(&self.build_dir)
     }
 }
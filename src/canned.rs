@@ -0,0 +1,223 @@
+use crate::kll::*;
+
+use serde_json::Value;
+
+const NAME_PLACEHOLDER: &str = "__NAME__";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Color {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+impl Color {
+    fn from_value(v: &Value) -> Option<Color> {
+        let channel = |c: &str| v.get(c).and_then(Value::as_u64).filter(|c| *c <= 255);
+        Some(Color {
+            r: channel("r")? as u8,
+            g: channel("g")? as u8,
+            b: channel("b")? as u8,
+        })
+    }
+
+    /// Linear blend towards `other`, 0.0 is self and 1.0 is other
+    fn blend(self, other: Color, amount: f32) -> Color {
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount).round() as u8;
+        Color {
+            r: mix(self.r, other.r),
+            g: mix(self.g, other.g),
+            b: mix(self.b, other.b),
+        }
+    }
+
+    fn to_kll(self) -> String {
+        format!("{},{},{}", self.r, self.g, self.b)
+    }
+}
+
+fn parse_amount(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(a) if (0.0..=1.0).contains(&a) => Ok(a),
+        _ => Err(format!("Invalid amount {}", s)),
+    }
+}
+
+/// Checks a user supplied value against the setting definition.
+/// `select` settings may be given by either the name or the value of an option.
+fn validate_setting(setting: &AnimationSetting, value: &Value) -> Result<Value, String> {
+    match setting._type.as_ref() {
+        "color" => match Color::from_value(value) {
+            Some(_) => Ok(value.clone()),
+            None => Err(format!(
+                "{} must be a color with r, g and b between 0 and 255",
+                setting.name
+            )),
+        },
+        _ => match &setting.values {
+            Some(values) => values
+                .iter()
+                .find(|option| {
+                    option.get("value") == Some(value) || option.get("name") == Some(value)
+                })
+                .map(|option| option.get("value").unwrap_or(option).clone())
+                .ok_or_else(|| format!("{} is not a valid value for {}", value, setting.name)),
+            None => Ok(value.clone()),
+        },
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Object(_) => match Color::from_value(value) {
+            Some(color) => color.to_kll(),
+            None => value.to_string(),
+        },
+        _ => value.to_string(),
+    }
+}
+
+/// Expands a single `${...}` placeholder
+///  - `${name}` the setting value
+///  - `${name!0.3}` a color darkened by the given amount
+///  - `${start:end:0.3}` a blend between two colors
+fn expand_placeholder(
+    placeholder: &str,
+    name: &str,
+    settings: &serde_json::Map<String, Value>,
+) -> Result<String, String> {
+    if placeholder == NAME_PLACEHOLDER {
+        return Ok(name.to_string());
+    }
+
+    let color = |n: &str| {
+        settings
+            .get(n)
+            .and_then(Color::from_value)
+            .ok_or_else(|| format!("Unknown color setting {}", n))
+    };
+
+    let parts = placeholder.split(':').collect::<Vec<_>>();
+    if parts.len() == 3 {
+        let amount = parse_amount(parts[2])?;
+        return Ok(color(parts[0])?.blend(color(parts[1])?, amount).to_kll());
+    }
+
+    let mut parts = placeholder.splitn(2, '!');
+    let setting = parts.next().unwrap_or("");
+    if let Some(amount) = parts.next() {
+        let black = Color { r: 0, g: 0, b: 0 };
+        return Ok(color(setting)?.blend(black, parse_amount(amount)?).to_kll());
+    }
+
+    settings
+        .get(setting)
+        .map(format_value)
+        .ok_or_else(|| format!("Unknown setting {}", setting))
+}
+
+fn expand_template(
+    template: &str,
+    name: &str,
+    settings: &serde_json::Map<String, Value>,
+) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated placeholder in {}", template))?;
+        out.push_str(&rest[..start]);
+        out.push_str(&expand_placeholder(
+            &rest[start + 2..start + end],
+            name,
+            settings,
+        )?);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Resolves the values for each configurable setting, falling back to the defaults
+fn canned_settings(
+    canned: &CannedAnimation,
+    values: Option<&serde_json::Map<String, Value>>,
+) -> Result<serde_json::Map<String, Value>, String> {
+    let mut settings = serde_json::Map::new();
+    if let Some(values) = values {
+        for (k, v) in values {
+            let setting = canned
+                .configurable
+                .iter()
+                .find(|s| &s.name == k)
+                .ok_or_else(|| format!("Unknown setting {}", k))?;
+            settings.insert(k.clone(), validate_setting(setting, v)?);
+        }
+    }
+
+    for setting in canned.configurable.iter() {
+        if !settings.contains_key(&setting.name) {
+            settings.insert(setting.name.clone(), setting.default.clone());
+        }
+    }
+    Ok(settings)
+}
+
+/// Turns an animation that references a canned template into a regular animation,
+/// the custom kll of the template goes along with it.
+pub fn expand_canned(
+    name: &str,
+    animation: &Animation,
+    config: &KllConfig,
+) -> Result<Animation, String> {
+    let canned_name = match &animation.canned {
+        Some(c) => c,
+        None => return Ok(animation.clone()),
+    };
+
+    let canned = config
+        .canned
+        .as_ref()
+        .and_then(|c| c.get(canned_name))
+        .ok_or_else(|| format!("Unknown canned animation {}", canned_name))?;
+
+    let settings = canned_settings(canned, animation.values.as_ref())?;
+    let expand = |s: &str| expand_template(s, name, &settings);
+
+    let mut kll_settings = expand(&canned.settings)?;
+    if !animation.settings.is_empty() {
+        kll_settings = format!("{}, {}", animation.settings, kll_settings);
+    }
+
+    let frames = canned
+        .frames
+        .iter()
+        .map(|f| expand(f))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let custom_kll = match &canned.custom_kll {
+        Some(c) => Some(expand(c)?),
+        None => None,
+    };
+
+    Ok(Animation {
+        _type: Some(canned._type.clone()),
+        frames,
+        settings: kll_settings,
+        canned: None,
+        values: None,
+        custom: custom_kll,
+    })
+}
+
+/// Checks every canned animation selection in the config
+pub fn validate_canned(config: &KllConfig) -> Result<(), String> {
+    if let Some(animations) = &config.animations {
+        for (name, animation) in animations {
+            expand_canned(name, animation, config).map_err(|e| format!("{}: {}", name, e))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use crate::dialect::{container_dialect, Dialect};
+use crate::kll::*;
+use crate::package::PackageInfo;
+
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// The configurator key that produced a line of a generated kll file
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeySource {
+    /// `MatrixKey::code`
+    pub code: String,
+    pub layer: usize,
+    pub action: Option<KeyAction>,
+}
+
+/// An error found in the build output
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// `kll`, `cmake` or `compiler`
+    pub source: String,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub token: Option<String>,
+    /// The generated kll line the error points at
+    pub text: Option<String>,
+    pub key: Option<KeySource>,
+}
+
+impl Diagnostic {
+    fn new(source: &'static str, message: &str) -> Self {
+        Diagnostic {
+            source: source.to_string(),
+            message: message.trim().to_string(),
+            file: None,
+            line: None,
+            column: None,
+            token: None,
+            text: None,
+            key: None,
+        }
+    }
+}
+
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a letter, e.g. `\x1b[1;31m`
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Leading number of a string, `12:5` -> 12
+fn leading_number(s: &str) -> Option<usize> {
+    let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// The number following a word, `... line 12 ...` -> 12
+fn number_after(line: &str, word: &str) -> Option<usize> {
+    let lower = line.to_lowercase();
+    lower
+        .match_indices(word)
+        .filter_map(|(i, _)| leading_number(lower[i + word.len()..].trim_start()))
+        .next()
+}
+
+/// First single quoted string, funcparserlib reports the offending token as `Name 'foo'`
+fn quoted(line: &str) -> Option<String> {
+    let start = line.find('\'')? + 1;
+    let end = start + line[start..].find('\'')?;
+    Some(line[start..end].to_string()).filter(|t| !t.is_empty())
+}
+
+/// A `path.kll` reference, optionally followed by `:line` and `:column`
+fn kll_reference(line: &str) -> Option<(String, Option<usize>, Option<usize>)> {
+    let end = line.find(".kll")? + ".kll".len();
+    let start = line[..end]
+        .rfind(|c: char| c.is_whitespace() || c == '\'' || c == '"' || c == '(')
+        .map_or(0, |i| i + 1);
+    let file = line[start..end].to_string();
+
+    let mut rest = line[end..].split(':').skip(1);
+    let line_no = rest.next().and_then(leading_number);
+    let column = line_no.and(rest.next().and_then(leading_number));
+    Some((file, line_no, column))
+}
+
+/// `path:line:column: error: message` as printed by gcc
+fn compiler_error(line: &str) -> Option<Diagnostic> {
+    let idx = line.find(": error:")?;
+    let mut parts = line[..idx].split(':');
+    let file = parts.next()?.to_string();
+    let line_no = parts.next().and_then(leading_number)?;
+    let column = parts.next().and_then(leading_number);
+
+    let source = if file.ends_with(".kll") {
+        "kll"
+    } else {
+        "compiler"
+    };
+    let message = &line[idx + ": error:".len()..];
+    let mut diagnostic = Diagnostic::new(source, message);
+    diagnostic.file = Some(file);
+    diagnostic.line = Some(line_no);
+    diagnostic.column = column;
+    diagnostic.token = quoted(message);
+    Some(diagnostic)
+}
+
+/// Errors reported by the kll compiler, e.g.
+/// `ERROR: Tokenize error in MD1-Standard-1.kll line 12 col 5 'U"FOO"'`
+fn kll_error(line: &str) -> Option<Diagnostic> {
+    let lower = line.to_lowercase();
+    if !lower.contains("error") || lower.starts_with("make") || lower.contains("***") {
+        return None;
+    }
+
+    let (file, line_no, column) = kll_reference(line)?;
+    let mut diagnostic = Diagnostic::new("kll", line);
+    diagnostic.file = Some(file);
+    diagnostic.line = line_no.or_else(|| number_after(line, "line"));
+    diagnostic.column = column
+        .or_else(|| number_after(line, "column"))
+        .or_else(|| number_after(line, "col"));
+    diagnostic.token = quoted(line);
+    Some(diagnostic)
+}
+
+/// `CMake Error at path:line (command):` followed by an indented message,
+/// or `CMake Error: message` on a single line
+fn cmake_error(lines: &[String]) -> Option<Diagnostic> {
+    let first = lines[0].trim_start();
+    if !first.starts_with("CMake Error") {
+        return None;
+    }
+
+    let rest = first["CMake Error".len()..].trim_start();
+    if let Some(message) = rest.strip_prefix(':') {
+        return Some(Diagnostic::new("cmake", message));
+    }
+
+    let mut message = Vec::new();
+    for line in lines[1..].iter() {
+        if line.trim().is_empty() {
+            if message.is_empty() {
+                continue;
+            }
+            break;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            break;
+        }
+        message.push(line.trim());
+    }
+
+    let mut diagnostic = Diagnostic::new("cmake", &message.join(" "));
+    if let Some(location) = rest.strip_prefix("at ") {
+        let location = location.split(" (").next().unwrap_or(location);
+        let (file, line_no) = match location.rfind(':') {
+            Some(i) => (&location[..i], leading_number(&location[i + 1..])),
+            None => (location, None),
+        };
+        diagnostic.file = Some(file.to_string());
+        diagnostic.line = line_no;
+    }
+    Some(diagnostic)
+}
+
+/// Finds the kll compiler, CMake and compiler errors in the captured build output
+pub fn parse_log(log: &str) -> Vec<Diagnostic> {
+    let lines: Vec<String> = log.lines().map(strip_ansi).collect();
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let diagnostic = cmake_error(&lines[i..])
+            .or_else(|| compiler_error(line))
+            .or_else(|| kll_error(line));
+
+        if let Some(diagnostic) = diagnostic {
+            // The compiler repeats itself, e.g. for each half of a split keyboard
+            let duplicate = diagnostics.iter().any(|d| {
+                d.message == diagnostic.message
+                    && d.file == diagnostic.file
+                    && d.line == diagnostic.line
+            });
+            if !duplicate {
+                diagnostics.push(diagnostic);
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Layer index from a generated file name, `MD1-Standard-2.kll` -> 2
+fn file_layer(name: &str) -> Option<usize> {
+    name.trim_end_matches(".kll")
+        .rsplit('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// The generated file a path from the build output refers to.
+/// Paths inside the container are longer, the longest matching name wins (`left/...`).
+fn find_file<'a>(files: &'a [KllFile], path: &str) -> Option<&'a KllFile> {
+    files
+        .iter()
+        .filter(|f| path == f.name || path.ends_with(&format!("/{}", f.name)))
+        .max_by_key(|f| f.name.len())
+}
+
+/// Finds the key behind a mapping (`U"A" : U"B";`) or trigger (`U"A" :+ ...;`) line
+fn line_source(
+    config: &KllConfig,
+    base_keys: &[Option<String>],
+    layer: usize,
+    text: &str,
+) -> Option<KeySource> {
+    let text = text.trim().trim_start_matches('#');
+    let trigger = text.split(" :").next()?.trim();
+
+    config
+        .matrix
+        .iter()
+        .zip(base_keys)
+        .filter_map(|(key, base)| Some((key, base.as_ref()?)))
+        .find(|(key, base)| {
+            let on_layer = key.layers.contains_key(&layer)
+                || key
+                    .triggers
+                    .as_ref()
+                    .is_some_and(|t| t.contains_key(&layer));
+            on_layer && format_key(base) == trigger
+        })
+        .map(|(key, _)| KeySource {
+            code: key.code.clone(),
+            layer,
+            action: key.layers.get(&layer).cloned(),
+        })
+}
+
+/// Points the diagnostics at the kll lines of the files that were built and the configurator
+/// keys behind them. Keys are left out when the base layout can't be read.
+pub fn map_diagnostics(
+    diagnostics: &mut [Diagnostic],
+    config: &KllConfig,
+    target: &Dialect,
+    files: &[KllFile],
+) {
+    if !diagnostics.iter().any(|d| d.source == "kll") {
+        return;
+    }
+
+    let base_keys = base_keys(config, target).unwrap_or_default();
+
+    for diagnostic in diagnostics.iter_mut().filter(|d| d.source == "kll") {
+        let file = match diagnostic.file.as_ref().and_then(|f| find_file(files, f)) {
+            Some(file) => file,
+            None => continue,
+        };
+        diagnostic.file = Some(file.name.clone());
+
+        // Without a line number, look for the offending token instead
+        let line = diagnostic.line.or_else(|| {
+            let token = diagnostic.token.as_ref()?;
+            file.content
+                .lines()
+                .position(|l| l.contains(token.as_str()))
+                .map(|i| i + 1)
+        });
+        let text = match line.and_then(|l| file.content.lines().nth(l.checked_sub(1)?)) {
+            Some(text) => text,
+            None => continue,
+        };
+
+        diagnostic.line = line;
+        diagnostic.text = Some(text.to_string());
+        diagnostic.key =
+            file_layer(&file.name).and_then(|layer| line_source(config, &base_keys, layer, text));
+    }
+}
+
+/// Structured errors for a failed build of `config` from the kll `files`
+pub fn diagnose(
+    log: &str,
+    config: &KllConfig,
+    target: &Dialect,
+    files: &[KllFile],
+) -> Vec<Diagnostic> {
+    let mut diagnostics = parse_log(log);
+    map_diagnostics(&mut diagnostics, config, target, files);
+    diagnostics
+}
+
+/// Structured errors of a failed build, read from its log once the build has exited.
+/// The split halves are copies of the packaged layers, errors in them point at the layer.
+pub fn build_diagnostics(log: &Path, package: &PackageInfo) -> Vec<Diagnostic> {
+    let log = match fs::read_to_string(log) {
+        Ok(log) => log,
+        Err(_) => return Vec::new(),
+    };
+    match serde_json::from_str::<KllConfig>(&package.config) {
+        Ok(config) => diagnose(
+            &log,
+            &config,
+            container_dialect(&package.container),
+            &package.kll_files,
+        ),
+        Err(_) => parse_log(&log),
+    }
+}
@@ -0,0 +1,112 @@
+use crate::keyboard::keyboard;
+use crate::kll::*;
+
+/// Rewrites a capability that isn't understood by an older firmware.
+/// A `replacement` of `None` comments the mapping out instead.
+pub struct CapabilityRewrite {
+    pub contains: &'static str,
+    pub replacement: Option<&'static str>,
+}
+
+/// The flavour of KLL understood by a range of firmware releases
+pub struct Dialect {
+    /// KLL language version
+    pub kll: &'static str,
+    /// Build containers that ship a firmware using this dialect
+    pub containers: &'static [&'static str],
+    /// Checked in order (ignoring whitespace), the first match wins
+    pub rewrites: &'static [CapabilityRewrite],
+    pub animations: bool,
+    pub triggers: bool,
+    pub leds: bool,
+}
+
+pub const LTS: Dialect = Dialect {
+    kll: "0.3",
+    containers: &["controller-050"],
+    rewrites: &[
+        // LED-
+        CapabilityRewrite {
+            contains: "ledControl(0,15)",
+            replacement: Some("ledControl( 3, 15, 0 )"),
+        },
+        // LED+
+        CapabilityRewrite {
+            contains: "ledControl(1,15)",
+            replacement: Some("ledControl( 4, 15, 0 )"),
+        },
+        // LED OFF
+        CapabilityRewrite {
+            contains: "ledControl(3,0)",
+            replacement: Some("ledControl( 5, 0, 0)"),
+        },
+        CapabilityRewrite {
+            contains: "ledControl",
+            replacement: None,
+        },
+        CapabilityRewrite {
+            contains: "animation_control",
+            replacement: None,
+        },
+    ],
+    animations: false,
+    triggers: false,
+    leds: false,
+};
+
+pub const LATEST: Dialect = Dialect {
+    kll: "0.5c",
+    containers: &[
+        "controller-054",
+        "controller-055",
+        "controller-056",
+        "controller-057",
+    ],
+    rewrites: &[],
+    animations: true,
+    triggers: true,
+    leds: true,
+};
+
+pub const DIALECTS: [&Dialect; 2] = [&LTS, &LATEST];
+
+/// The dialect to target for a build container, unknown containers get the latest one
+pub fn container_dialect(container: &str) -> &'static Dialect {
+    DIALECTS
+        .iter()
+        .cloned()
+        .find(|d| d.containers.contains(&container))
+        .unwrap_or(&LATEST)
+}
+
+impl Dialect {
+    pub fn rewrite(&self, mapping: &mut KllMapping) {
+        let capability = match &mapping.result {
+            KllResult::Capability(c) => c.replace(' ', ""),
+            KllResult::Key(_) => return,
+        };
+
+        if let Some(rewrite) = self
+            .rewrites
+            .iter()
+            .find(|r| capability.contains(r.contains))
+        {
+            match rewrite.replacement {
+                Some(r) => mapping.result = KllResult::Capability(r.to_string()),
+                None => mapping.disabled = true,
+            }
+        }
+    }
+
+    /// Base layout file for a keyboard, some keyboards have a variant for this dialect
+    pub fn base_layout(&self, name: &str, base: &str) -> String {
+        // Between LTS and Latest the scancode mapping for White Fox changed. Previously there
+        //  was a single all encompassing map, now there are a number of smaller ones that have
+        //  different (sensible) default scancode mappings.
+        let variant = keyboard(name).and_then(|k| k.base_variants.get(self.kll));
+        match variant {
+            Some(suffix) => format!("./layouts/{}-{}.{}.json", name, base, suffix),
+            None => format!("./layouts/{}-{}.json", name, base),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Length of the DFU suffix appended to every `.dfu.bin`
+pub const DFU_SUFFIX_LEN: usize = 16;
+
+/// Flash usage the configurator should warn about
+pub const FLASH_WARNING: f64 = 0.9;
+
+/// Shortest printable run considered when looking for the version string
+const MIN_STRING_LEN: usize = 4;
+
+/// Fields of the DFU 1.1 file suffix
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DfuSuffix {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bcd_device: u16,
+    pub bcd_dfu: u16,
+    /// The suffix CRC matches the contents of the file
+    pub crc_valid: bool,
+}
+
+/// CRC-32 used by the DFU suffix, without the final inversion
+pub fn dfu_crc(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    crc
+}
+
+fn u16_le(data: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([data[i], data[i + 1]])
+}
+
+impl DfuSuffix {
+    /// Reads the suffix from the end of a `.dfu.bin`.
+    /// Layout: bcdDevice, idProduct, idVendor, bcdDFU, `UFD`, bLength, dwCRC (little endian).
+    pub fn parse(data: &[u8]) -> Option<DfuSuffix> {
+        if data.len() < DFU_SUFFIX_LEN {
+            return None;
+        }
+        let suffix = &data[data.len() - DFU_SUFFIX_LEN..];
+        if &suffix[8..11] != b"UFD" || suffix[11] as usize != DFU_SUFFIX_LEN {
+            return None;
+        }
+
+        let crc = u32::from_le_bytes([suffix[12], suffix[13], suffix[14], suffix[15]]);
+        Some(DfuSuffix {
+            bcd_device: u16_le(suffix, 0),
+            product_id: u16_le(suffix, 2),
+            vendor_id: u16_le(suffix, 4),
+            bcd_dfu: u16_le(suffix, 6),
+            crc_valid: dfu_crc(&data[..data.len() - 4]) == crc,
+        })
+    }
+}
+
+/// A version tag or `git describe` output, `v0.5.7` or `v0.5.7-3-g1a2b3c4`
+fn is_version(token: &str) -> bool {
+    let mut chars = token.chars();
+    chars.next() == Some('v')
+        && chars.next().is_some_and(|c| c.is_ascii_digit())
+        && token.contains('.')
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+' || c == '_')
+}
+
+/// The first version-like string embedded in the image
+pub fn version_string(image: &[u8]) -> Option<String> {
+    image
+        .split(|b| !(0x20..0x7f).contains(b))
+        .filter(|run| run.len() >= MIN_STRING_LEN)
+        .flat_map(|run| {
+            String::from_utf8_lossy(run)
+                .split_whitespace()
+                .map(|t| {
+                    t.trim_matches(|c: char| !c.is_ascii_alphanumeric())
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        })
+        .find(|token| is_version(token))
+}
+
+/// A firmware binary produced by the build
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareImage {
+    /// Name in the zip, `kiibohd.dfu.bin` or `left_kiibohd.dfu.bin`
+    pub name: String,
+    /// Size of the image written to flash, without the DFU suffix
+    pub bytes: u64,
+    pub dfu: Option<DfuSuffix>,
+    pub version: Option<String>,
+}
+
+impl FirmwareImage {
+    pub fn parse(name: &str, data: &[u8]) -> FirmwareImage {
+        let dfu = DfuSuffix::parse(data);
+        let image = match dfu {
+            Some(_) => &data[..data.len() - DFU_SUFFIX_LEN],
+            None => data,
+        };
+        FirmwareImage {
+            name: name.to_string(),
+            bytes: image.len() as u64,
+            dfu,
+            version: version_string(image),
+        }
+    }
+}
+
+/// What is known about the firmware of a successful build
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareInfo {
+    pub images: Vec<FirmwareImage>,
+    /// Flash available to the firmware, from the keyboard registry
+    pub flash_size: Option<u64>,
+    /// Size of the largest image
+    pub flash_used: u64,
+    /// `flash_used / flash_size`
+    pub flash_usage: Option<f64>,
+    /// The largest image uses more than `FLASH_WARNING` of the flash
+    pub flash_warning: bool,
+}
+
+impl FirmwareInfo {
+    pub fn new(images: Vec<FirmwareImage>, flash_size: Option<u64>) -> FirmwareInfo {
+        let flash_used = images.iter().map(|i| i.bytes).max().unwrap_or(0);
+        let flash_usage = flash_size
+            .filter(|size| *size > 0)
+            .map(|size| flash_used as f64 / size as f64);
+        FirmwareInfo {
+            images,
+            flash_size,
+            flash_used,
+            flash_usage,
+            flash_warning: flash_usage.is_some_and(|usage| usage >= FLASH_WARNING),
+        }
+    }
+
+    /// The image the version and USB ids are reported from, the left half of split keyboards
+    pub fn primary(&self) -> Option<&FirmwareImage> {
+        self.images.first()
+    }
+}
+
+/// Inspects the `.dfu.bin` files in a packaged zip.
+/// None when the zip can't be read or has no firmware in it.
+pub fn firmware_info(path: &Path, flash_size: Option<u64>) -> Option<FirmwareInfo> {
+    let mut archive = ZipArchive::new(File::open(path).ok()?).ok()?;
+
+    let mut images = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = match archive.by_index(i) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if file.is_dir() || !file.name().ends_with(".dfu.bin") {
+            continue;
+        }
+        let mut data = Vec::new();
+        if file.read_to_end(&mut data).is_ok() {
+            images.push(FirmwareImage::parse(file.name(), &data));
+        }
+    }
+
+    match images.is_empty() {
+        true => None,
+        false => Some(FirmwareInfo::new(images, flash_size)),
+    }
+}
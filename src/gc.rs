@@ -0,0 +1,219 @@
+use crate::jobs::*;
+use crate::signing::{signature_file, SIGNATURE_EXTENSION};
+
+use chrono::prelude::*;
+use rusqlite::types::ToSql;
+use serde_derive::Serialize;
+use std::collections::hash_map::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Limits on the build artifacts kept on disk, unset limits are not enforced
+#[derive(Clone, Debug)]
+pub struct GcPolicy {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<chrono::Duration>,
+    pub build_dir: PathBuf,
+    pub log_dir: PathBuf,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Removal {
+    pub file: String,
+    /// Job that produced the file, if it is still known
+    pub hash: Option<String>,
+    pub bytes: u64,
+    /// `age` or `size`, whichever budget the file was over
+    pub reason: &'static str,
+    pub last_used: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub removed: Vec<Removal>,
+    pub freed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+struct Artifact {
+    file: String,
+    hash: Option<String>,
+    bytes: u64,
+    last_used: DateTime<Utc>,
+}
+
+/// Every file in the build directory, least recently used first.
+/// Zips of finished jobs were last used when downloaded (or built), other files by mtime.
+fn artifacts(table: &JobTable, policy: &GcPolicy) -> Vec<Artifact> {
+    let owners: HashMap<String, &Job> = table
+        .jobs
+        .values()
+        .map(|job| (job.artifact(), job))
+        .collect();
+
+    let entries = match fs::read_dir(&policy.build_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut artifacts: Vec<Artifact> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let metadata = e.metadata().ok().filter(|m| m.is_file())?;
+            let file = e.file_name().into_string().ok()?;
+            // Signatures go along with their zip, unless the zip is already gone
+            let signed = file.strip_suffix(&format!(".{}", SIGNATURE_EXTENSION));
+            if signed.is_some_and(|zip| policy.build_dir.join(zip).exists()) {
+                return None;
+            }
+            // A signature is removed with its zip, so it counts towards the zip's size
+            let signature = fs::metadata(signature_file(&e.path())).map_or(0, |m| m.len());
+            let (hash, last_used) = match owners.get(&file) {
+                // Still being written
+                Some(job) if !job.status.is_finished() => return None,
+                Some(job) => (
+                    Some(job.hash.clone()),
+                    job.downloaded.or(job.finished).unwrap_or(job.created),
+                ),
+                None => (None, metadata.modified().ok()?.into()),
+            };
+            Some(Artifact {
+                file,
+                hash,
+                bytes: metadata.len() + signature,
+                last_used,
+            })
+        })
+        .collect();
+    artifacts.sort_by_key(|a| a.last_used);
+    artifacts
+}
+
+/// Picks the artifacts that are over the age or size budgets and, unless this is a dry run,
+/// deletes them along with their jobs, logs and kll files. Groups are forgotten with their
+/// last job.
+pub fn collect(jobs: &Mutex<JobTable>, policy: &GcPolicy, dry_run: bool) -> GcReport {
+    let mut table = jobs.lock().expect("Could not lock mutex");
+    let artifacts = artifacts(&table, policy);
+
+    let now = Utc::now();
+    let mut remaining: u64 = artifacts.iter().map(|a| a.bytes).sum();
+    let mut removed = Vec::new();
+    for artifact in artifacts {
+        let reason = if policy
+            .max_age
+            .is_some_and(|age| now - artifact.last_used > age)
+        {
+            "age"
+        } else if policy.max_bytes.is_some_and(|max| remaining > max) {
+            "size"
+        } else {
+            // Everything after this was used more recently
+            break;
+        };
+
+        remaining -= artifact.bytes;
+        removed.push(Removal {
+            file: artifact.file,
+            hash: artifact.hash,
+            bytes: artifact.bytes,
+            reason,
+            last_used: artifact.last_used,
+        });
+    }
+
+    if !dry_run {
+        for removal in removed.iter() {
+            remove(&mut table, policy, removal);
+        }
+        remove_stale_configs(&table);
+        for id in table.expire_groups() {
+            println!(" > GC: forgetting group {}", id);
+        }
+    }
+
+    GcReport {
+        dry_run,
+        freed_bytes: removed.iter().map(|r| r.bytes).sum(),
+        removed,
+        remaining_bytes: remaining,
+    }
+}
+
+fn remove(table: &mut JobTable, policy: &GcPolicy, removal: &Removal) {
+    println!(
+        " > GC: removing {} ({} bytes, {})",
+        removal.file, removal.bytes, removal.reason
+    );
+    let path = policy.build_dir.join(&removal.file);
+    if let Err(e) = fs::remove_file(&path) {
+        println!("Error: Failed to remove {}: {}", removal.file, e);
+        return;
+    }
+    let _ = fs::remove_file(signature_file(&path));
+
+    if let Some(hash) = &removal.hash {
+        table.forget(hash);
+        let _ = fs::remove_file(log_file(&policy.log_dir.to_string_lossy(), hash));
+    }
+
+    if let Some(db) = table.db() {
+        let args: &[&dyn ToSql] = &[
+            &removal.file,
+            &removal.hash,
+            &(removal.bytes as i64),
+            &removal.reason,
+            &removal.last_used,
+            &Utc::now(),
+        ];
+        db.execute(
+            "INSERT INTO Removals (file, hash, bytes, reason, last_used, removed)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            args,
+        )
+        .unwrap_or_else(|_| {
+            println!("Error: Failed to record removal of {}", removal.file);
+            0
+        });
+    }
+}
+
+/// The kll files are only needed until the build finishes
+fn remove_stale_configs(table: &JobTable) {
+    let entries = match fs::read_dir(&table.config_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let hash = entry.file_name().to_string_lossy().to_string();
+        let building = table
+            .get(&hash)
+            .is_some_and(|job| !job.status.is_finished());
+        if !building {
+            if let Err(e) = fs::remove_dir_all(entry.path()) {
+                println!("Error: Failed to remove {:?}: {}", entry.path(), e);
+            }
+        }
+    }
+}
+
+/// Runs the garbage collector every `interval`
+pub fn collector(jobs: Arc<Mutex<JobTable>>, policy: GcPolicy, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let report = collect(&jobs, &policy, false);
+        if !report.removed.is_empty() {
+            println!(
+                "GC: removed {} files, freed {} bytes, {} bytes remaining",
+                report.removed.len(),
+                report.freed_bytes,
+                report.remaining_bytes
+            );
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use crate::kll::*;
+
+/// USB keyboard usage names (HID usage page 0x07) as understood by the KLL compiler.
+/// Names are compared case-insensitively, ignoring spaces and underscores, and are
+/// emitted with the spelling listed here.
+#[rustfmt::skip]
+const USB_KEYS: &[&str] = &[
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R",
+    "S", "T", "U", "V", "W", "X", "Y", "Z", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0",
+    "ENTER", "ESC", "BACKSPACE", "TAB", "SPACE", "MINUS", "EQUAL", "LBRACE", "RBRACE",
+    "BACKSLASH", "NUMBER", "SEMICOLON", "QUOTE", "BACKTICK", "COMMA", "PERIOD", "SLASH",
+    "CAPSLOCK", "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+    "PRINTSCREEN", "SCROLLLOCK", "PAUSE", "INSERT", "HOME", "PAGEUP", "DELETE", "END",
+    "PAGEDOWN", "RIGHT", "LEFT", "DOWN", "UP", "NUMLOCK", "P/", "P*", "P-", "P+", "PENTER",
+    "P1", "P2", "P3", "P4", "P5", "P6", "P7", "P8", "P9", "P0", "P.", "ISO/", "APP", "POWER",
+    "P=", "F13", "F14", "F15", "F16", "F17", "F18", "F19", "F20", "F21", "F22", "F23", "F24",
+    "EXECUTE", "HELP", "MENU", "SELECT", "STOP", "AGAIN", "UNDO", "CUT", "COPY", "PASTE",
+    "FIND", "MUTE", "VOLUMEUP", "VOLUMEDOWN", "P,", "P=AS400", "INTERNATIONAL1",
+    "INTERNATIONAL2", "INTERNATIONAL3", "INTERNATIONAL4", "INTERNATIONAL5", "INTERNATIONAL6",
+    "INTERNATIONAL7", "INTERNATIONAL8", "INTERNATIONAL9", "LANG1", "LANG2", "LANG3", "LANG4",
+    "LANG5", "LANG6", "LANG7", "LANG8", "LANG9", "ALTERASE", "SYSREQ", "CANCEL", "CLEAR",
+    "PRIOR", "RETURN", "SEPARATOR", "OUT", "OPER", "CLEARAGAIN", "CRSEL", "EXSEL", "P00",
+    "P000", "THOUSANDSEPARATOR", "DECIMALSEPARATOR", "CURRENCYUNIT", "CURRENCYSUBUNIT", "P(",
+    "P)", "P{", "P}", "PTAB", "PBACKSPACE", "PA", "PB", "PC", "PD", "PE", "PF", "PXOR", "P^",
+    "P%", "P<", "P>", "P&", "P&&", "P|", "P||", "P:", "P#", "PSPACE", "P@", "P!",
+    "PMEMSTORE", "PMEMRECALL", "PMEMCLEAR", "PMEMADD", "PMEMSUBTRACT", "PMEMMULTIPLY",
+    "PMEMDIVIDE", "P+/-", "PCLEAR", "PCLEARENTRY", "PBINARY", "POCTAL", "PDECIMAL",
+    "PHEXADECIMAL", "LCTRL", "LSHIFT", "LALT", "LGUI", "RCTRL", "RSHIFT", "RALT", "RGUI",
+    // Kiibohd layer keys
+    "FUN1", "FUN2", "FUN3", "FUN4", "FUN5", "FUN6", "FUN7", "FUN8", "FUN9", "FUN10", "FUN11",
+    "FUN12", "FUN13", "FUN14", "FUN15", "FUN16", "LCK1", "LCK2", "LCK3", "LCK4", "LCK5",
+    "LCK6", "LCK7", "LCK8", "LCK9", "LCK10", "LCK11", "LCK12", "LCK13", "LCK14", "LCK15",
+    "LCK16", "LAT1", "LAT2", "LAT3", "LAT4", "LAT5", "LAT6", "LAT7", "LAT8", "LAT9", "LAT10",
+    "LAT11", "LAT12", "LAT13", "LAT14", "LAT15", "LAT16", "FUNCTION1", "FUNCTION2",
+    "FUNCTION3", "FUNCTION4", "FUNCTION5", "FUNCTION6", "FUNCTION7", "FUNCTION8", "FUNCTION9",
+    "FUNCTION10", "FUNCTION11", "FUNCTION12", "FUNCTION13", "FUNCTION14", "FUNCTION15",
+    "FUNCTION16",
+];
+
+/// Alternative spellings accepted for USB keyboard usages
+const USB_ALIASES: &[(&str, &str)] = &[
+    ("ESCAPE", "ESC"),
+    ("EQUALS", "EQUAL"),
+    ("=", "EQUAL"),
+    ("-", "MINUS"),
+    ("LBRACKET", "LBRACE"),
+    ("LEFTBRACE", "LBRACE"),
+    ("[", "LBRACE"),
+    ("RBRACKET", "RBRACE"),
+    ("RIGHTBRACE", "RBRACE"),
+    ("]", "RBRACE"),
+    ("\\", "BACKSLASH"),
+    ("HASH", "NUMBER"),
+    (";", "SEMICOLON"),
+    ("'", "QUOTE"),
+    ("APOSTROPHE", "QUOTE"),
+    ("`", "BACKTICK"),
+    ("TILDE", "BACKTICK"),
+    ("GRAVE", "BACKTICK"),
+    (",", "COMMA"),
+    (".", "PERIOD"),
+    ("/", "SLASH"),
+    ("CAPS", "CAPSLOCK"),
+    ("PRINT", "PRINTSCREEN"),
+    ("SCROLL", "SCROLLLOCK"),
+    ("INS", "INSERT"),
+    ("DEL", "DELETE"),
+    ("PGUP", "PAGEUP"),
+    ("PGDN", "PAGEDOWN"),
+    ("KEYPADSLASH", "P/"),
+    ("KEYPADASTERISK", "P*"),
+    ("KEYPADMINUS", "P-"),
+    ("KEYPADPLUS", "P+"),
+    ("KEYPADENTER", "PENTER"),
+    ("KEYPAD1", "P1"),
+    ("KEYPAD2", "P2"),
+    ("KEYPAD3", "P3"),
+    ("KEYPAD4", "P4"),
+    ("KEYPAD5", "P5"),
+    ("KEYPAD6", "P6"),
+    ("KEYPAD7", "P7"),
+    ("KEYPAD8", "P8"),
+    ("KEYPAD9", "P9"),
+    ("KEYPAD0", "P0"),
+    ("KEYPADPERIOD", "P."),
+    ("KEYPADEQUAL", "P="),
+    ("KEYPADCOMMA", "P,"),
+    ("ISOSLASH", "ISO/"),
+    ("CONTEXT", "APP"),
+    ("CTRL", "LCTRL"),
+    ("SHIFT", "LSHIFT"),
+    ("ALT", "LALT"),
+    ("OPT", "LALT"),
+    ("OPTION", "LALT"),
+    ("ALTGR", "RALT"),
+    ("GUI", "LGUI"),
+    ("CMD", "LGUI"),
+    ("WIN", "LGUI"),
+    ("SUPER", "LGUI"),
+];
+
+/// Consumer control usage names (HID usage page 0x0C)
+const CONSUMER_KEYS: &[&str] = &[
+    "POWER",
+    "RESET",
+    "SLEEP",
+    "MENU",
+    "MENU PICK",
+    "MENU UP",
+    "MENU DOWN",
+    "MENU LEFT",
+    "MENU RIGHT",
+    "MENU ESCAPE",
+    "BRIGHTNESS INCREMENT",
+    "BRIGHTNESS DECREMENT",
+    "BACKLIGHT TOGGLE",
+    "BRIGHTNESS MIN",
+    "BRIGHTNESS MAX",
+    "BRIGHTNESS AUTO",
+    "PLAY",
+    "PAUSE",
+    "RECORD",
+    "FAST FORWARD",
+    "REWIND",
+    "SCAN NEXT TRACK",
+    "SCAN PREVIOUS TRACK",
+    "STOP",
+    "EJECT",
+    "RANDOM PLAY",
+    "REPEAT",
+    "STOP EJECT",
+    "PAUSE PLAY",
+    "MUTE",
+    "BASS BOOST",
+    "LOUDNESS",
+    "VOLUME UP",
+    "VOLUME DOWN",
+    "CALCULATOR",
+    "FILE BROWSER",
+    "INTERNET BROWSER",
+    "EMAIL READER",
+    "CALENDAR",
+    "WORD PROCESSOR",
+    "SPREADSHEET",
+    "PRESENTATION APP",
+    "TERMINAL LOCK",
+    "CONTROL PANEL",
+    "TASK MANAGER",
+    "SCREEN SAVER",
+    "SEARCH",
+    "HOME",
+    "BACK",
+    "FORWARD",
+    "REFRESH",
+    "BOOKMARKS",
+    "UNDO",
+    "CUT",
+    "COPY",
+    "PASTE",
+];
+
+const CONSUMER_ALIASES: &[(&str, &str)] = &[
+    ("PLAY PAUSE", "PAUSE PLAY"),
+    ("NEXT TRACK", "SCAN NEXT TRACK"),
+    ("PREVIOUS TRACK", "SCAN PREVIOUS TRACK"),
+    ("VOL UP", "VOLUME UP"),
+    ("VOL DOWN", "VOLUME DOWN"),
+    ("PAUSEPLAY", "PAUSE PLAY"),
+    ("SCANNEXTTRACK", "SCAN NEXT TRACK"),
+    ("SCANPREVIOUSTRACK", "SCAN PREVIOUS TRACK"),
+    ("VOLUMEUP", "VOLUME UP"),
+    ("VOLUMEDOWN", "VOLUME DOWN"),
+];
+
+/// System control usage names (HID usage page 0x01)
+const SYSTEM_KEYS: &[&str] = &[
+    "POWER DOWN",
+    "SLEEP",
+    "WAKE UP",
+    "CONTEXT MENU",
+    "MAIN MENU",
+    "APP MENU",
+    "MENU HELP",
+    "MENU EXIT",
+    "MENU SELECT",
+    "MENU RIGHT",
+    "MENU LEFT",
+    "MENU UP",
+    "MENU DOWN",
+    "COLD RESTART",
+    "WARM RESTART",
+    "DOCK",
+    "UNDOCK",
+    "SPEAKER MUTE",
+    "HIBERNATE",
+    "DISPLAY INVERT",
+    "DISPLAY INTERNAL",
+    "DISPLAY EXTERNAL",
+    "DISPLAY BOTH",
+    "DISPLAY DUAL",
+    "DISPLAY TOGGLE",
+    "DISPLAY SWAP",
+];
+
+const SYSTEM_ALIASES: &[(&str, &str)] = &[("WAKEUP", "WAKE UP"), ("POWER", "POWER DOWN")];
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| *c != ' ' && *c != '_')
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+/// The catalog spelling of a name, aliases resolve to the name they stand for.
+/// An exact match is preferred over a normalized one.
+fn lookup(
+    name: &str,
+    keys: &[&'static str],
+    aliases: &[(&'static str, &'static str)],
+) -> Option<&'static str> {
+    let names = keys.iter().map(|k| (*k, *k)).chain(aliases.iter().copied());
+    let found = names.clone().find(|(k, _)| *k == name).or_else(|| {
+        let name = normalize(name);
+        names.into_iter().find(|(k, _)| normalize(k) == name)
+    });
+    found.map(|(_, canonical)| canonical)
+}
+
+/// `U+2318` style unicode output
+fn is_unicode(name: &str) -> bool {
+    match name.strip_prefix("U+") {
+        Some(code) => !code.is_empty() && code.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+impl KllKey {
+    /// Whether the name is in the catalog for its usage page, empty (unassigned) keys are allowed
+    pub fn is_known(&self) -> bool {
+        self.canonical().is_some()
+    }
+
+    /// The key spelled as in the catalog, None for unknown names
+    pub fn canonical(&self) -> Option<KllKey> {
+        match self {
+            KllKey::Usb(k) if k.is_empty() || is_unicode(k) => Some(self.clone()),
+            KllKey::Usb(k) => lookup(k, USB_KEYS, USB_ALIASES).map(|k| KllKey::Usb(k.to_string())),
+            KllKey::Consumer(k) => {
+                lookup(k, CONSUMER_KEYS, CONSUMER_ALIASES).map(|k| KllKey::Consumer(k.to_string()))
+            }
+            KllKey::System(k) => {
+                lookup(k, SYSTEM_KEYS, SYSTEM_ALIASES).map(|k| KllKey::System(k.to_string()))
+            }
+        }
+    }
+}
+
+pub fn unknown_key(name: &str, code: &str, layer: usize) -> String {
+    format!(
+        "Unknown key name {} (matrix code {}, layer {})",
+        name, code, layer
+    )
+}
+
+/// Every key a layer maps to must be a known HID usage
+pub fn validate_keys(config: &KllConfig) -> Result<(), String> {
+    for key in config.matrix.iter() {
+        for (layer, action) in key.layers.iter() {
+            if let KllResult::Key(k) = KllResult::from_config(&action.key) {
+                if !k.is_known() {
+                    return Err(unknown_key(&action.key, &key.code, *layer));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,463 @@
+use crate::kll::*;
+
+use indexmap::IndexMap;
+use std::fs;
+use std::path::Path;
+
+const HEADER_FIELDS: [&str; 9] = [
+    "Name",
+    "Variant",
+    "Layout",
+    "Base",
+    "Version",
+    "Author",
+    "KLL",
+    "Date",
+    "Generator",
+];
+
+#[derive(Debug, PartialEq)]
+enum Statement {
+    Assignment(String, String),
+    Mapping(String, String),
+    Trigger(String, String),
+    AnimationSettings(String, String),
+    AnimationFrame(String, String),
+    Custom(String),
+}
+
+/// The contents of a single .kll file, before it has been resolved against a base layout
+#[derive(Default)]
+struct KllLayer {
+    assignments: Vec<(String, String)>,
+    mappings: Vec<(String, String)>,
+    triggers: Vec<(String, String)>,
+    animations: IndexMap<String, Animation>,
+    custom: Vec<String>,
+}
+
+impl KllLayer {
+    /// Blank lines after a custom block (beyond the separating one) are part of the block
+    fn close_custom(&mut self, comments: &[String]) {
+        let blank = comments.iter().take_while(|c| c.is_empty()).count();
+        for _ in 1..blank {
+            self.custom.push("".to_string());
+        }
+    }
+
+    /// Adds a line to the kll that goes along with an animation
+    fn push_animation_custom(&mut self, name: &str, line: &str) {
+        let animation = self.animations.get_mut(name).unwrap();
+        animation.custom = Some(match animation.custom.take() {
+            Some(custom) => format!("{}\n{}", custom, line),
+            None => line.to_string(),
+        });
+    }
+
+    /// Blank lines after an animation (beyond the separating one) belong to the animation
+    fn close_animation(&mut self, name: &str, comments: &[String]) {
+        let blank = comments.iter().take_while(|c| c.is_empty()).count();
+        for _ in 1..blank {
+            self.push_animation_custom(name, "");
+        }
+    }
+}
+
+fn unquote(s: &str) -> Option<&str> {
+    let s = s.trim();
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') && !s[1..s.len() - 1].contains('"')
+    {
+        Some(&s[1..s.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// Inverse of `format_key`
+fn parse_key(s: &str) -> Option<String> {
+    let s = s.trim();
+    if let Some(k) = s.strip_prefix("CONS") {
+        unquote(k).map(|k| format!("CONS:{}", k))
+    } else if let Some(k) = s.strip_prefix("SYS") {
+        unquote(k).map(|k| format!("SYS:{}", k))
+    } else if let Some(k) = s.strip_prefix('U') {
+        unquote(k).map(|k| k.to_string())
+    } else {
+        None
+    }
+}
+
+/// Splits on the first occurrence of `sep` that is not inside of a quoted string
+fn split_unquoted<'a>(s: &'a str, sep: &str) -> Option<(&'a str, &'a str)> {
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && s[i..].starts_with(sep) {
+            return Some((&s[..i], &s[i + sep.len()..]));
+        }
+    }
+    None
+}
+
+fn ends_statement(s: &str) -> bool {
+    let mut quoted = false;
+    let mut comment = false;
+    let mut end = false;
+    for c in s.chars() {
+        match c {
+            '\n' => comment = false,
+            _ if comment => {}
+            '"' => quoted = !quoted,
+            '#' if !quoted => comment = true,
+            ';' if !quoted => end = true,
+            c if !c.is_whitespace() => end = false,
+            _ => {}
+        }
+    }
+    end
+}
+
+fn parse_statement(s: &str) -> Statement {
+    let body = s.trim().trim_end_matches(';').trim();
+
+    if body.starts_with("A[") {
+        if let Some((lhs, rhs)) = split_unquoted(body, "<=") {
+            let lhs = lhs.trim();
+            let inner = lhs[2..].trim_end_matches(']');
+            let mut parts = inner.splitn(2, ',');
+            let name = parts.next().unwrap_or("").trim().to_string();
+            return match parts.next() {
+                Some(_frame) => {
+                    // Frames are often aligned with whitespace, keep it
+                    let frame = rhs.trim_end();
+                    let frame = frame.strip_prefix(' ').unwrap_or(frame);
+                    Statement::AnimationFrame(name, frame.to_string())
+                }
+                None => Statement::AnimationSettings(name, rhs.trim().to_string()),
+            };
+        }
+    }
+
+    if let Some((lhs, rhs)) = split_unquoted(body, ":+") {
+        if let Some(key) = parse_key(lhs) {
+            return Statement::Trigger(key, rhs.trim().to_string());
+        }
+    }
+
+    if let Some((lhs, rhs)) = split_unquoted(body, ":") {
+        if let Some(key) = parse_key(lhs) {
+            let action = match parse_key(rhs) {
+                Some(action) => action,
+                None => format!("#:{}", rhs.trim()),
+            };
+            return Statement::Mapping(key, action);
+        }
+    }
+
+    if let Some((lhs, rhs)) = split_unquoted(body, "=") {
+        let name = lhs.trim();
+        let is_ident = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if let (true, Some(value)) = (is_ident, unquote(rhs)) {
+            return Statement::Assignment(name.to_string(), value.to_string());
+        }
+    }
+
+    Statement::Custom(s.trim_end().to_string())
+}
+
+fn parse_layer(content: &str) -> KllLayer {
+    let mut layer = KllLayer::default();
+
+    // Comments are only kept if they belong to a custom block or an animation
+    let mut comments: Vec<String> = Vec::new();
+    let mut pending = String::new();
+    let mut last_animation: Option<String> = None;
+    let mut prev_animation: Option<String> = None;
+    let mut last_custom = false;
+
+    for line in content.lines() {
+        if pending.is_empty() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                comments.push("".to_string());
+                last_animation = None;
+                continue;
+            }
+            if trimmed.starts_with('#') {
+                match &last_animation {
+                    Some(name) if !line.starts_with("###") => {
+                        let animation = layer.animations.get_mut(name).unwrap();
+                        animation.frames.push(line.to_string());
+                    }
+                    _ => comments.push(line.to_string()),
+                }
+                continue;
+            }
+        } else {
+            pending.push('\n');
+        }
+
+        pending.push_str(line);
+        if !ends_statement(&pending) {
+            continue;
+        }
+
+        let statement = parse_statement(&pending);
+        pending.clear();
+
+        // Statements right after the frames go along with the animation, e.g. the trigger
+        //  that starts a canned animation
+        if let (Some(name), Statement::Custom(s)) = (&last_animation, &statement) {
+            layer.push_animation_custom(name, s);
+            comments.clear();
+            continue;
+        }
+
+        let is_custom = matches!(statement, Statement::Custom(_));
+        if last_custom && !is_custom {
+            layer.close_custom(&comments);
+        }
+        last_custom = is_custom;
+
+        let is_animation = matches!(
+            statement,
+            Statement::AnimationSettings(..) | Statement::AnimationFrame(..)
+        );
+        if let (Some(name), true) = (&prev_animation, is_animation) {
+            layer.close_animation(name, &comments);
+        }
+        prev_animation = None;
+
+        last_animation = None;
+        match statement {
+            Statement::Assignment(name, value) => layer.assignments.push((name, value)),
+            Statement::Mapping(key, action) => layer.mappings.push((key, action)),
+            Statement::Trigger(key, action) => layer.triggers.push((key, action)),
+            Statement::AnimationSettings(name, settings) => {
+                layer.animations.insert(
+                    name.clone(),
+                    Animation {
+                        _type: None,
+                        frames: Vec::new(),
+                        settings,
+                        canned: None,
+                        values: None,
+                        custom: None,
+                    },
+                );
+                last_animation = Some(name.clone());
+                prev_animation = Some(name);
+            }
+            Statement::AnimationFrame(name, frame) => {
+                let animation = layer.animations.entry(name.clone()).or_insert(Animation {
+                    _type: None,
+                    frames: Vec::new(),
+                    settings: "".to_string(),
+                    canned: None,
+                    values: None,
+                    custom: None,
+                });
+                animation.frames.push(frame);
+                last_animation = Some(name.clone());
+                prev_animation = Some(name);
+            }
+            Statement::Custom(s) => {
+                if layer.custom.is_empty() {
+                    // Drop the blank lines separating the block from the previous statements
+                    let leading = comments.iter().skip_while(|c| c.is_empty());
+                    layer.custom.extend(leading.cloned());
+                } else {
+                    layer.custom.extend(comments.iter().cloned());
+                }
+                layer.custom.push(s);
+            }
+        }
+        comments.clear();
+    }
+
+    if !pending.trim().is_empty() {
+        layer.custom.push(pending);
+    } else if last_custom {
+        layer.close_custom(&comments);
+    }
+
+    layer
+}
+
+/// Layer index from a generated filename (`<Name>-<Layout>-<n>.kll`)
+fn layer_index(filename: &str) -> Option<usize> {
+    let stem = Path::new(filename).file_stem()?.to_str()?;
+    stem.rsplit('-').next()?.parse().ok()
+}
+
+/// Rebuilds a KllConfig from a set of .kll layer files.
+/// Keys are resolved against the base layout given in the header of the first layer.
+pub fn import_kll(files: &[KllFile]) -> Result<KllConfig, String> {
+    // The per-half copies of a split keyboard are identical to the top level layers
+    let mut layers: Vec<(usize, KllLayer)> = files
+        .iter()
+        .filter(|f| !is_split_half(f))
+        .enumerate()
+        .map(|(i, f)| (layer_index(&f.name).unwrap_or(i), parse_layer(&f.content)))
+        .collect();
+    layers.sort_by_key(|(n, _)| *n);
+
+    let base_layer = match layers.first() {
+        Some((_, layer)) => layer,
+        None => return Err("No kll files provided".to_string()),
+    };
+
+    let mut header: IndexMap<String, String> = IndexMap::new();
+    let mut defines = Vec::new();
+    for (name, value) in &base_layer.assignments {
+        if HEADER_FIELDS.contains(&name.as_ref()) {
+            header.insert(name.clone(), value.clone());
+        } else {
+            defines.push(Define {
+                name: name.clone(),
+                value: value.clone(),
+            });
+        }
+    }
+
+    let field = |name: &str| header.get(name).cloned().unwrap_or_default();
+    let header = KllHeader {
+        name: field("Name"),
+        variant: header.get("Variant").cloned(),
+        layout: field("Layout"),
+        base: field("Base"),
+        version: field("Version"),
+        author: field("Author"),
+        kll: field("KLL"),
+        date: field("Date"),
+        generator: field("Generator"),
+        other: serde_json::Map::new(),
+    };
+
+    let name = header.name.replace(' ', "_");
+    if name.is_empty() {
+        return Err("Missing Name in kll header".to_string());
+    }
+
+    let base_file = format!("./layouts/{}-{}.json", name, header.base);
+    let default: KllConfig = {
+        let contents = fs::read_to_string(base_file)
+            .map_err(|_| format!("Unknown base layout {}-{}", name, header.base))?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())?
+    };
+
+    // Keys are matched by their key in the base layout, each physical key may only be
+    //  used once per layer
+    let mut matrix: Vec<(usize, String, MatrixKey)> = Vec::new();
+    let mut custom = IndexMap::new();
+    let mut animations = IndexMap::new();
+    for (n, layer) in layers.iter() {
+        let n = *n;
+        for (key, action) in &layer.mappings {
+            let idx = resolve_key(&mut matrix, &default.matrix, key, |k| {
+                k.layers.contains_key(&n)
+            })
+            .ok_or_else(|| format!("Unknown key {} in layer {}", key, n))?;
+            matrix[idx].2.layers.insert(
+                n,
+                KeyAction {
+                    key: action.clone(),
+                    label: None,
+                },
+            );
+        }
+
+        for (key, action) in &layer.triggers {
+            let idx = resolve_key(&mut matrix, &default.matrix, key, |k| match &k.triggers {
+                Some(t) => t.contains_key(&n),
+                None => false,
+            })
+            .ok_or_else(|| format!("Unknown key {} in layer {}", key, n))?;
+            let _type = if action.starts_with("A[") {
+                "animation"
+            } else {
+                "custom"
+            };
+            let triggers = matrix[idx].2.triggers.get_or_insert_with(IndexMap::new);
+            triggers.insert(
+                n,
+                Trigger {
+                    action: action.clone(),
+                    label: action.clone(),
+                    _type: _type.to_string(),
+                },
+            );
+        }
+
+        if !layer.custom.is_empty() {
+            custom.insert(n, layer.custom.join("\n"));
+        }
+
+        for (k, v) in layer.animations.iter() {
+            animations.insert(k.clone(), v.clone());
+        }
+    }
+
+    // Keys that only have triggers, or are only mapped on higher layers, keep their base mapping
+    let matrix = matrix
+        .into_iter()
+        .map(|(_, base_key, mut key)| {
+            if !key.layers.contains_key(&0) {
+                key.layers.insert(
+                    0,
+                    KeyAction {
+                        key: base_key,
+                        label: None,
+                    },
+                );
+                key.layers.sort_keys();
+            }
+            key
+        })
+        .collect();
+
+    Ok(KllConfig {
+        matrix,
+        custom: Some(custom),
+        animations: Some(animations),
+        canned: None,
+        defines: Some(defines),
+        header,
+        leds: None,
+    })
+}
+
+fn resolve_key<F>(
+    matrix: &mut Vec<(usize, String, MatrixKey)>,
+    default: &[MatrixKey],
+    key: &str,
+    used: F,
+) -> Option<usize>
+where
+    F: Fn(&MatrixKey) -> bool,
+{
+    let existing = matrix
+        .iter()
+        .position(|(_, base_key, k)| base_key == key && !used(k));
+    if existing.is_some() {
+        return existing;
+    }
+
+    // Split keyboards re-use scan codes for each half, so track the base key by position
+    let (idx, def_key) = default.iter().enumerate().find(|(idx, def_key)| {
+        def_key.layers.get(&0).map(|a| a.key.as_str()) == Some(key)
+            && !matrix.iter().any(|(i, _, _)| i == idx)
+    })?;
+
+    matrix.push((
+        idx,
+        key.to_string(),
+        MatrixKey {
+            layers: IndexMap::new(),
+            triggers: None,
+            ..def_key.clone()
+        },
+    ));
+    Some(matrix.len() - 1)
+}
@@ -0,0 +1,881 @@
+use crate::build::{BuildBackend, BuildInfo, BuildJob};
+use crate::diagnostics::{build_diagnostics, Diagnostic};
+use crate::firmware::{firmware_info, FirmwareInfo};
+use crate::keyboard::keyboard;
+use crate::package::{package, PackageInfo};
+use crate::signing::Signer;
+
+use chrono::prelude::*;
+use iron::response::WriteBody;
+use rusqlite::{types::ToSql, Connection};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the watchdog looks for builds that have run too long
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Building,
+    Success,
+    Failed,
+    /// Killed after running longer than the build timeout
+    TimedOut,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Building)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Building => "building",
+            JobStatus::Success => "success",
+            JobStatus::Failed => "failed",
+            JobStatus::TimedOut => "timedout",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<JobStatus> {
+        [
+            JobStatus::Queued,
+            JobStatus::Building,
+            JobStatus::Success,
+            JobStatus::Failed,
+            JobStatus::TimedOut,
+            JobStatus::Cancelled,
+        ]
+        .iter()
+        .cloned()
+        .find(|status| status.name() == name)
+    }
+}
+
+/// Queued jobs with a higher priority are started first
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Priority> {
+        [Priority::Low, Priority::Normal, Priority::High]
+            .iter()
+            .cloned()
+            .find(|priority| priority.name() == name)
+    }
+}
+
+/// A firmware build, keyed by the hash of its configuration
+#[derive(Clone)]
+pub struct Job {
+    pub hash: String,
+    pub container: String,
+    /// Name of the zip when the build succeeds
+    pub output_file: String,
+    pub status: JobStatus,
+    pub priority: Priority,
+    pub process: Option<Arc<dyn BuildJob>>,
+    pub created: DateTime<Utc>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Last time the zip was fetched, used to pick what to garbage collect
+    pub downloaded: Option<DateTime<Utc>>,
+    /// SHA-256 of the zip, set once it has been packaged
+    pub checksum: Option<String>,
+    /// Metadata of the firmware binaries, set once a successful build has been packaged
+    pub firmware: Option<FirmwareInfo>,
+    /// Errors found in the log of a failed build
+    pub diagnostics: Vec<Diagnostic>,
+    /// The build has exited and its zip is being written, it can no longer be stopped
+    packaging: bool,
+    /// Clients blocked until the build finishes
+    waiters: usize,
+    /// Cancel tokens of the clients polling for the result
+    tokens: Vec<String>,
+}
+
+impl Job {
+    pub fn new(hash: &str, container: &str, output_file: &str, priority: Priority) -> Job {
+        Job {
+            hash: hash.to_string(),
+            container: container.to_string(),
+            output_file: output_file.to_string(),
+            status: JobStatus::Queued,
+            priority,
+            process: None,
+            created: Utc::now(),
+            started: None,
+            finished: None,
+            downloaded: None,
+            checksum: None,
+            firmware: None,
+            diagnostics: Vec::new(),
+            packaging: false,
+            waiters: 0,
+            tokens: Vec::new(),
+        }
+    }
+
+    /// The zip that will be served for this job, failed builds produce an error zip
+    pub fn artifact(&self) -> String {
+        match self.status {
+            JobStatus::Failed | JobStatus::TimedOut | JobStatus::Cancelled => {
+                self.output_file.replace(".zip", "_error.zip")
+            }
+            _ => self.output_file.clone(),
+        }
+    }
+
+    /// Whether the job is still being built by this process
+    fn runs(&self, process: &Arc<dyn BuildJob>) -> bool {
+        let current = self.process.as_ref().map(|p| Arc::as_ptr(p) as *const ());
+        current == Some(Arc::as_ptr(process) as *const ())
+    }
+
+    /// Builds that were stopped can be started again by a new request
+    pub fn is_stopped(&self) -> bool {
+        matches!(self.status, JobStatus::TimedOut | JobStatus::Cancelled)
+    }
+
+    pub fn finish(&mut self, success: bool) {
+        self.end(match success {
+            true => JobStatus::Success,
+            false => JobStatus::Failed,
+        });
+    }
+
+    fn end(&mut self, status: JobStatus) {
+        self.status = status;
+        self.finished = Some(Utc::now());
+        self.process = None;
+        self.packaging = false;
+        self.waiters = 0;
+        self.tokens.clear();
+    }
+
+    /// Whether any client still wants the result of the build
+    fn is_wanted(&self) -> bool {
+        self.waiters > 0 || !self.tokens.is_empty()
+    }
+}
+
+/// What the backend needs to start a queued job
+pub struct PendingBuild {
+    pub info: BuildInfo,
+    pub log: PathBuf,
+    /// Kept so an interrupted build can be started again after a restart
+    pub config: String,
+    pub minimal: bool,
+    pub package: PackageInfo,
+}
+
+/// A job loaded from the jobs database along with the configuration it was built from
+pub struct StoredJob {
+    pub job: Job,
+    pub config: String,
+    pub minimal: bool,
+}
+
+impl StoredJob {
+    /// Columns read by `from_row`, in order
+    const COLUMNS: &'static str = "hash, container, output_file, status, priority, created, started, finished, downloaded, checksum, firmware, diagnostics, config, minimal";
+
+    fn from_row(row: &rusqlite::Row) -> Self {
+        let status: String = row.get(3);
+        let priority: String = row.get(4);
+        let firmware: Option<String> = row.get(10);
+        let diagnostics: Option<String> = row.get(11);
+        StoredJob {
+            job: Job {
+                hash: row.get(0),
+                container: row.get(1),
+                output_file: row.get(2),
+                status: JobStatus::from_name(&status).unwrap_or(JobStatus::Failed),
+                priority: Priority::from_name(&priority).unwrap_or_default(),
+                process: None,
+                created: row.get(5),
+                started: row.get(6),
+                finished: row.get(7),
+                downloaded: row.get(8),
+                checksum: row.get(9),
+                firmware: firmware.and_then(|f| serde_json::from_str(&f).ok()),
+                diagnostics: diagnostics
+                    .and_then(|d| serde_json::from_str(&d).ok())
+                    .unwrap_or_default(),
+                packaging: false,
+                waiters: 0,
+                tokens: Vec::new(),
+            },
+            config: row.get(12),
+            minimal: row.get(13),
+        }
+    }
+}
+
+/// All known jobs along with the queue of builds waiting for a free worker
+pub struct JobTable {
+    pub jobs: HashMap<String, Job>,
+    /// Hashes of queued jobs, in the order they will be started
+    queue: Vec<String>,
+    pending: HashMap<String, PendingBuild>,
+    running: usize,
+    pub max_running: usize,
+    /// Builds running longer than this are killed
+    pub timeout: Option<Duration>,
+    /// Cancel a build once every client waiting on it has disconnected
+    pub cancel_on_disconnect: bool,
+    /// Holds a `<hash>` directory of kll files for each job
+    pub config_dir: PathBuf,
+    /// Where the zips are written
+    pub build_dir: PathBuf,
+    /// Signs the zips of successful builds when set
+    pub signer: Option<Arc<Signer>>,
+    /// Jobs started by a multi-version build, group id -> (version, job hash)
+    groups: HashMap<String, Vec<(String, String)>>,
+    /// Every change to a job is saved here when set
+    db: Option<Connection>,
+}
+
+impl JobTable {
+    pub fn new(max_running: usize, config_dir: &Path, build_dir: &Path) -> JobTable {
+        JobTable {
+            jobs: HashMap::new(),
+            queue: Vec::new(),
+            pending: HashMap::new(),
+            running: 0,
+            max_running,
+            timeout: None,
+            cancel_on_disconnect: false,
+            config_dir: config_dir.to_path_buf(),
+            build_dir: build_dir.to_path_buf(),
+            signer: None,
+            groups: HashMap::new(),
+            db: None,
+        }
+    }
+
+    /// Starts saving jobs to the database, returns the jobs saved by a previous run.
+    /// They aren't added to the table, see `restore` and `submit`. Saved groups are loaded
+    /// right away, call `expire_groups` once the jobs have been restored.
+    pub fn open(&mut self, db: Connection) -> Vec<StoredJob> {
+        let args: &[&dyn ToSql] = &[];
+        let stored = {
+            let query = format!("SELECT {} FROM Jobs", StoredJob::COLUMNS);
+            let mut stmt = db.prepare(&query).unwrap();
+            let rows = stmt.query_map(args, StoredJob::from_row).unwrap();
+            rows.filter_map(|r| r.ok()).collect()
+        };
+        {
+            let mut stmt = db.prepare("SELECT id, members FROM Groups").unwrap();
+            let rows = stmt
+                .query_map(args, |row| {
+                    let members: String = row.get(1);
+                    (row.get::<_, String>(0), serde_json::from_str(&members).ok())
+                })
+                .unwrap();
+            for (id, members) in rows.filter_map(|r| r.ok()) {
+                if let Some(members) = members {
+                    self.groups.insert(id, members);
+                }
+            }
+        }
+        self.db = Some(db);
+        stored
+    }
+
+    /// Adds a finished job without building it
+    pub fn restore(&mut self, job: Job) {
+        let hash = job.hash.clone();
+        self.jobs.insert(hash.clone(), job);
+        self.save(&hash);
+    }
+
+    /// Removes a finished job, the next request for it will start a new build
+    pub fn forget(&mut self, hash: &str) {
+        self.jobs.remove(hash);
+        if let Some(db) = self.db.as_ref() {
+            let args: &[&dyn ToSql] = &[&hash];
+            db.execute("DELETE FROM Jobs WHERE hash = ?", args)
+                .unwrap_or_else(|_| {
+                    println!("Error: Failed to remove job {} from jobs db", hash);
+                    0
+                });
+        }
+    }
+
+    /// Remembers which job was started for each version of a multi-version build
+    pub fn register_group(&mut self, id: &str, members: Vec<(String, String)>) {
+        if let Some(db) = self.db.as_ref() {
+            let args: &[&dyn ToSql] = &[&id, &serde_json::to_string(&members).unwrap()];
+            db.execute(
+                "INSERT OR REPLACE INTO Groups (id, members) VALUES (?, ?)",
+                args,
+            )
+            .unwrap_or_else(|_| {
+                println!("Error: Failed to insert group {} into jobs db", id);
+                0
+            });
+        }
+        self.groups.insert(id.to_string(), members);
+    }
+
+    /// The (version, job hash) of each build in the group
+    pub fn group(&self, id: &str) -> Option<&Vec<(String, String)>> {
+        self.groups.get(id)
+    }
+
+    /// Removes the groups whose jobs have all been forgotten, returns their ids
+    pub fn expire_groups(&mut self) -> Vec<String> {
+        let jobs = &self.jobs;
+        let expired: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, members)| members.iter().all(|(_, hash)| !jobs.contains_key(hash)))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired.iter() {
+            self.groups.remove(id);
+            if let Some(db) = self.db.as_ref() {
+                let args: &[&dyn ToSql] = &[id];
+                db.execute("DELETE FROM Groups WHERE id = ?", args)
+                    .unwrap_or_else(|_| {
+                        println!("Error: Failed to remove group {} from jobs db", id);
+                        0
+                    });
+            }
+        }
+        expired
+    }
+
+    /// Marks the job that produced a zip as recently used
+    pub fn downloaded(&mut self, artifact: &str) {
+        let hash = self
+            .jobs
+            .values_mut()
+            .find(|job| job.status.is_finished() && job.artifact() == artifact)
+            .map(|job| {
+                job.downloaded = Some(Utc::now());
+                job.hash.clone()
+            });
+        if let Some(hash) = hash {
+            self.save(&hash);
+        }
+    }
+
+    /// Configuration json and minimal flag the job was submitted with
+    pub fn stored_config(&self, hash: &str) -> Option<(String, bool)> {
+        let args: &[&dyn ToSql] = &[&hash];
+        self.db
+            .as_ref()?
+            .query_row(
+                "SELECT config, minimal FROM Jobs WHERE hash = ?",
+                args,
+                |row| (row.get(0), row.get(1)),
+            )
+            .ok()
+    }
+
+    pub fn db(&self) -> Option<&Connection> {
+        self.db.as_ref()
+    }
+
+    /// Updates the saved state of a job
+    fn save(&self, hash: &str) {
+        let (db, job) = match (self.db.as_ref(), self.jobs.get(hash)) {
+            (Some(db), Some(job)) => (db, job),
+            _ => return,
+        };
+
+        let firmware = job
+            .firmware
+            .as_ref()
+            .map(|f| serde_json::to_string(f).unwrap());
+        let diagnostics = serde_json::to_string(&job.diagnostics).unwrap();
+        let args: &[&dyn ToSql] = &[
+            &job.artifact(),
+            &job.status.name(),
+            &job.priority.name(),
+            &job.started,
+            &job.finished,
+            &job.downloaded,
+            &job.checksum,
+            &firmware,
+            &diagnostics,
+            &job.hash,
+        ];
+        db.execute(
+            "UPDATE Jobs SET artifact = ?, status = ?, priority = ?, started = ?, finished = ?,
+             downloaded = ?, checksum = ?, firmware = ?, diagnostics = ? WHERE hash = ?",
+            args,
+        )
+        .unwrap_or_else(|_| {
+            println!("Error: Failed to update job {} in jobs db", hash);
+            0
+        });
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Job> {
+        self.jobs.get(hash)
+    }
+
+    /// Adds a new job to the back of the queue for its priority, replacing any stopped job
+    pub fn submit(&mut self, job: Job, build: PendingBuild) {
+        if let Some(db) = self.db.as_ref() {
+            let firmware = job
+                .firmware
+                .as_ref()
+                .map(|f| serde_json::to_string(f).unwrap());
+            let args: &[&dyn ToSql] = &[
+                &job.hash,
+                &job.container,
+                &job.output_file,
+                &job.artifact(),
+                &job.status.name(),
+                &job.priority.name(),
+                &build.config,
+                &build.minimal,
+                &job.created,
+                &job.started,
+                &job.finished,
+                &job.downloaded,
+                &job.checksum,
+                &firmware,
+            ];
+            db.execute(
+                "INSERT OR REPLACE INTO Jobs (hash, container, output_file, artifact, status, priority, config, minimal, created, started, finished, downloaded, checksum, firmware)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                args,
+            )
+            .unwrap_or_else(|_| {
+                println!("Error: Failed to insert job {} into jobs db", job.hash);
+                0
+            });
+        }
+
+        let hash = job.hash.clone();
+        self.jobs.insert(hash.clone(), job);
+        self.pending.insert(hash.clone(), build);
+        self.queue.push(hash);
+        self.sort_queue();
+    }
+
+    /// Another client asked for a queued job, it may move ahead if it has a higher priority
+    pub fn raise_priority(&mut self, hash: &str, priority: Priority) {
+        if let Some(job) = self.jobs.get_mut(hash) {
+            if job.status == JobStatus::Queued && job.priority < priority {
+                job.priority = priority;
+                self.sort_queue();
+                self.save(hash);
+            }
+        }
+    }
+
+    /// Number of queued jobs that will be started before this one
+    pub fn position(&self, hash: &str) -> Option<usize> {
+        self.queue.iter().position(|h| h == hash)
+    }
+
+    /// Registers a client that polls for the result of an unfinished job.
+    /// Returns the token the client needs to cancel the build.
+    pub fn subscribe(&mut self, hash: &str) -> Option<String> {
+        let job = match self.jobs.get_mut(hash) {
+            Some(job) if !job.status.is_finished() => job,
+            _ => return None,
+        };
+        let token = cancel_token(hash);
+        job.tokens.push(token.clone());
+        Some(token)
+    }
+
+    /// A polling client gave up on the job, the build is only stopped once no other client
+    /// is waiting on it. Returns whether the build was stopped.
+    pub fn unsubscribe(&mut self, hash: &str, token: &str) -> Result<bool, String> {
+        let job = match self.jobs.get_mut(hash) {
+            Some(job) if !job.status.is_finished() => job,
+            _ => return Err("job already finished".to_string()),
+        };
+        match job.tokens.iter().position(|t| t == token) {
+            Some(i) => job.tokens.remove(i),
+            None => return Err("invalid cancel token".to_string()),
+        };
+        if job.is_wanted() {
+            println!(" > Build {} is still wanted by other clients", hash);
+            return Ok(false);
+        }
+        Ok(self.cancel(hash, JobStatus::Cancelled))
+    }
+
+    /// Stops a queued or running build and removes its kll files.
+    /// Returns false if the job doesn't exist, has already finished or is being packaged.
+    pub fn cancel(&mut self, hash: &str, status: JobStatus) -> bool {
+        let job = match self.jobs.get_mut(hash) {
+            Some(job) if !job.status.is_finished() && !job.packaging => job,
+            _ => return false,
+        };
+
+        println!(" > Stopping build {} ({})", hash, status.name());
+        if let Some(process) = job.process.as_ref() {
+            if let Err(e) = process.kill() {
+                println!("Error: Failed to kill build {}: {}", hash, e);
+            }
+        }
+        job.end(status);
+        self.save(hash);
+
+        self.queue.retain(|h| h != hash);
+        self.pending.remove(hash);
+
+        let config_dir = self.config_dir.join(hash);
+        if config_dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&config_dir) {
+                println!("Error: Failed to remove {:?}: {}", config_dir, e);
+            }
+        }
+        true
+    }
+
+    fn sort_queue(&mut self) {
+        // Stable sort, jobs with the same priority stay in FIFO order
+        let jobs = &self.jobs;
+        self.queue
+            .sort_by_key(|h| std::cmp::Reverse(jobs.get(h).map(|j| j.priority)));
+    }
+
+    fn next(&mut self) -> Option<(String, PendingBuild)> {
+        if self.running >= self.max_running || self.queue.is_empty() {
+            return None;
+        }
+        let hash = self.queue.remove(0);
+        let build = self.pending.remove(&hash)?;
+        Some((hash, build))
+    }
+}
+
+/// Starts queued jobs until every worker is busy.
+/// Each started job gets a thread that records the result and starts the next job.
+pub fn dispatch(jobs: &Arc<Mutex<JobTable>>, backend: &Arc<dyn BuildBackend>) {
+    loop {
+        // The job and its worker are claimed under the lock, the build is started without it
+        //  since checking out a release can take a while
+        let (hash, build, container, output_file, started) = {
+            let mut table = jobs.lock().expect("Could not lock mutex");
+            let (hash, build) = match table.next() {
+                Some(next) => next,
+                None => return,
+            };
+            let job = match table.jobs.get_mut(&hash) {
+                Some(job) => job,
+                None => continue,
+            };
+            job.status = JobStatus::Building;
+            job.started = Some(Utc::now());
+            let (container, output_file) = (job.container.clone(), job.output_file.clone());
+            let started = job.started;
+            table.running += 1;
+            table.save(&hash);
+            (hash, build, container, output_file, started)
+            // drop lock
+        };
+
+        println!(" > Starting build {} in container {}", hash, container);
+        let PendingBuild {
+            info,
+            log,
+            package: contents,
+            ..
+        } = build;
+        let spawned = backend.spawn(&container, info, &hash, &output_file, &log);
+
+        let mut table = jobs.lock().expect("Could not lock mutex");
+        // Stopped while starting, and maybe even claimed again by a new request
+        let claimed = table.jobs.get_mut(&hash).filter(|job| {
+            job.status == JobStatus::Building && job.process.is_none() && job.started == started
+        });
+        let process = match (spawned, claimed) {
+            (Ok(process), Some(job)) => {
+                job.process = Some(process.clone());
+                process
+            }
+            (Ok(process), None) => {
+                println!(" > Build {} was stopped while starting", hash);
+                table.running -= 1;
+                drop(table);
+                if let Err(e) = process.kill() {
+                    println!("Error: Failed to kill build {}: {}", hash, e);
+                }
+                let _ = process.wait();
+                continue;
+            }
+            (Err(e), claimed) => {
+                println!("Error: Failed to start build {}: {}", hash, e);
+                if let Some(job) = claimed {
+                    job.finish(false);
+                    table.save(&hash);
+                }
+                table.running -= 1;
+                continue;
+            }
+        };
+
+        let jobs = jobs.clone();
+        let backend = backend.clone();
+        let build_dir = table.build_dir.clone();
+        let signer = table.signer.clone();
+        drop(table);
+        thread::spawn(move || {
+            let success = process.wait().unwrap_or(false);
+            // Stopped jobs have already been finished (and may have been resubmitted)
+            let zip = {
+                let mut table = jobs.lock().expect("Could not lock mutex");
+                match table.jobs.get_mut(&hash) {
+                    Some(job) if job.runs(&process) => {
+                        job.packaging = true;
+                        let mut finished = job.clone();
+                        finished.finish(success);
+                        Some(build_dir.join(finished.artifact()))
+                    }
+                    _ => {
+                        table.running -= 1;
+                        None
+                    }
+                }
+                // drop lock
+            };
+
+            // Packaged before anyone sees the job as finished, without holding the lock.
+            // The worker is busy until then.
+            if let Some(zip) = zip {
+                let checksum = match package(&zip, &contents, success, &log) {
+                    Ok(checksum) => Some(checksum),
+                    Err(e) => {
+                        println!("Error: Failed to package {:?}: {}", zip, e);
+                        None
+                    }
+                };
+                let mut firmware = None;
+                let mut diagnostics = Vec::new();
+                if success {
+                    let flash_size = keyboard(&contents.keyboard).and_then(|k| k.flash_size);
+                    firmware = firmware_info(&zip, flash_size);
+                    if let Some(signer) = &signer {
+                        if let Err(e) = signer.sign_file(&zip) {
+                            println!("Error: Failed to sign {:?}: {}", zip, e);
+                        }
+                    }
+                } else {
+                    diagnostics = build_diagnostics(&log, &contents);
+                }
+                backend.clean_up(&hash);
+
+                let mut table = jobs.lock().expect("Could not lock mutex");
+                table.running -= 1;
+                if let Some(job) = table.jobs.get_mut(&hash).filter(|job| job.runs(&process)) {
+                    job.finish(success);
+                    job.checksum = checksum;
+                    job.firmware = firmware;
+                    job.diagnostics = diagnostics;
+                    table.save(&hash);
+                }
+                // drop lock
+            }
+            dispatch(&jobs, &backend);
+        });
+    }
+}
+
+/// Blocks until the job has finished building.
+/// Any number of threads may wait on the same job.
+pub fn wait_for_job(jobs: &Arc<Mutex<JobTable>>, hash: &str) -> Option<Job> {
+    let mut waiting = false;
+    loop {
+        let process = {
+            let jobs = jobs.lock().expect("Could not lock mutex");
+            let job = jobs.get(hash)?;
+            if job.status.is_finished() {
+                if waiting {
+                    println!(" > Done");
+                }
+                return Some(job.clone());
+            }
+            job.process.clone()
+            // drop lock
+        };
+
+        match process {
+            Some(process) if !waiting => {
+                println!(" > Waiting for task to finish {}", process.id());
+                waiting = true;
+                let _ = process.wait();
+            }
+            // Queued, or waiting for the result to be recorded
+            _ => thread::sleep(Duration::from_millis(500)),
+        }
+    }
+}
+
+/// Kills builds that have been running for longer than the table's timeout
+pub fn watchdog(jobs: Arc<Mutex<JobTable>>) {
+    loop {
+        thread::sleep(WATCHDOG_INTERVAL);
+
+        let mut table = jobs.lock().expect("Could not lock mutex");
+        let timeout = match table.timeout.map(chrono::Duration::from_std) {
+            Some(Ok(timeout)) => timeout,
+            _ => continue,
+        };
+
+        let now = Utc::now();
+        let expired: Vec<String> = table
+            .jobs
+            .values()
+            .filter(|job| job.status == JobStatus::Building && !job.packaging)
+            .filter(|job| job.started.is_some_and(|started| now - started > timeout))
+            .map(|job| job.hash.clone())
+            .collect();
+        for hash in expired {
+            table.cancel(&hash, JobStatus::TimedOut);
+        }
+        // drop lock
+    }
+}
+
+/// Registers a client that is blocked on the job, it is released when the `Waiter` is dropped
+pub fn waiter(jobs: &Arc<Mutex<JobTable>>, hash: &str) -> Waiter {
+    let mut table = jobs.lock().expect("Could not lock mutex");
+    if let Some(job) = table.jobs.get_mut(hash) {
+        job.waiters += 1;
+    }
+    Waiter {
+        jobs: jobs.clone(),
+        hash: hash.to_string(),
+    }
+}
+
+/// A client blocked on a job
+pub struct Waiter {
+    jobs: Arc<Mutex<JobTable>>,
+    hash: String,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut table = self.jobs.lock().expect("Could not lock mutex");
+        let abandoned = match table.jobs.get_mut(&self.hash) {
+            Some(job) if !job.status.is_finished() && job.waiters > 0 => {
+                job.waiters -= 1;
+                !job.is_wanted()
+            }
+            _ => false,
+        };
+        if abandoned && table.cancel_on_disconnect {
+            println!(" > Every client waiting on {} has disconnected", self.hash);
+            table.cancel(&self.hash, JobStatus::Cancelled);
+        }
+    }
+}
+
+/// Unguessable token, only handed to the client that asked for the build
+fn cancel_token(hash: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut hasher = Sha256::new();
+    hasher.update(hash.as_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.update(now.as_nanos().to_le_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn log_file(log_dir: &str, hash: &str) -> PathBuf {
+    Path::new(log_dir).join(format!("{}.log", hash))
+}
+
+/// Tails a build log as Server-Sent Events until the job finishes.
+/// Each line of output is sent as a `data:` event, followed by a final `end` event
+/// carrying the job status.
+pub struct LogStream {
+    pub jobs: Arc<Mutex<JobTable>>,
+    pub hash: String,
+    pub path: PathBuf,
+    /// Keeps the client counted as waiting until the stream ends
+    pub _waiter: Waiter,
+}
+
+impl LogStream {
+    fn status(&self) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().expect("Could not lock mutex");
+        jobs.get(&self.hash).map(|job| job.status)
+    }
+}
+
+impl WriteBody for LogStream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        let mut file = None;
+        let mut line = Vec::new();
+        loop {
+            // Checked before reading so no output is missed after the build exits
+            let status = self.status();
+
+            if file.is_none() {
+                file = File::open(&self.path).ok().map(BufReader::new);
+            }
+            if let Some(file) = file.as_mut() {
+                // Partial lines are kept until the rest of the line has been written
+                while file.read_until(b'\n', &mut line)? > 0 {
+                    if line.ends_with(b"\n") {
+                        let text = String::from_utf8_lossy(&line);
+                        write!(res, "data: {}\n\n", text.trim_end())?;
+                        line.clear();
+                    }
+                }
+            }
+
+            match status {
+                Some(status) if !status.is_finished() => {}
+                status => {
+                    if !line.is_empty() {
+                        write!(res, "data: {}\n\n", String::from_utf8_lossy(&line))?;
+                    }
+                    let status = status.map_or("unknown", JobStatus::name);
+                    write!(res, "event: end\ndata: {}\n\n", status)?;
+                    return res.flush();
+                }
+            }
+
+            // Keep-alive comment, fails once the client has gone away
+            write!(res, ":\n\n")?;
+            res.flush()?;
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+}
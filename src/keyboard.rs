@@ -0,0 +1,90 @@
+use crate::kll::KllConfig;
+
+use indexmap::IndexMap;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::sync::OnceLock;
+
+const KEYBOARDS_FILE: &str = "./keyboards.json";
+
+/// How the keys of a layout are paired with the keys of its base layout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanCodeMatch {
+    /// Compare the values, `0x0A` and `10` are the same key
+    #[default]
+    Numeric,
+    /// Compare the scan code strings as written
+    Exact,
+}
+
+/// A keyboard the firmware can be built for
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Keyboard {
+    /// Name used in layout headers and files
+    pub name: String,
+    /// Other header names that refer to this keyboard
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Script in the controller's `Keyboards` directory
+    pub build_script: String,
+    /// Built as a left and a right half
+    #[serde(default)]
+    pub split: bool,
+    /// Maps added before the base layer
+    #[serde(default = "default_extra_maps")]
+    pub extra_maps: Vec<String>,
+    #[serde(default)]
+    pub scan_codes: ScanCodeMatch,
+    /// Base layout suffix per KLL dialect (`<Name>-<Base>.<suffix>.json`), for firmware
+    /// releases that used different scan codes (the WhiteFox LTS bases)
+    #[serde(default)]
+    pub base_variants: IndexMap<String, String>,
+    /// Flash available to the firmware after the bootloader, in bytes
+    #[serde(default)]
+    pub flash_size: Option<u64>,
+}
+
+fn default_extra_maps() -> Vec<String> {
+    vec!["stdFuncMap".to_string()]
+}
+
+impl Keyboard {
+    /// The fields that change what gets built, the build digest covers only these
+    pub fn build_inputs(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "build_script": self.build_script,
+            "split": self.split,
+            "extra_maps": self.extra_maps,
+            "scan_codes": self.scan_codes,
+            "base_variants": self.base_variants,
+        })
+    }
+
+    pub fn is_named(&self, name: &str) -> bool {
+        let name = name.replace(' ', "_").to_lowercase();
+        self.name.to_lowercase() == name || self.aliases.iter().any(|a| a.to_lowercase() == name)
+    }
+}
+
+/// Every supported keyboard, loaded from `keyboards.json` on first use
+pub fn keyboards() -> &'static [Keyboard] {
+    static KEYBOARDS: OnceLock<Vec<Keyboard>> = OnceLock::new();
+    KEYBOARDS.get_or_init(|| {
+        let contents = fs::read_to_string(KEYBOARDS_FILE).expect("Could not read keyboards.json");
+        serde_json::from_str(&contents).expect("Invalid keyboards.json")
+    })
+}
+
+/// Looks up a keyboard by name or alias, ignoring case
+pub fn keyboard(name: &str) -> Option<&'static Keyboard> {
+    keyboards().iter().find(|k| k.is_named(name))
+}
+
+pub fn validate_keyboard(config: &KllConfig) -> Result<(), String> {
+    match keyboard(&config.header.name) {
+        Some(_) => Ok(()),
+        None => Err(format!("Unknown keyboard {}", config.header.name)),
+    }
+}
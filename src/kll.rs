@@ -1,6 +1,12 @@
+use crate::canned::expand_canned;
+use crate::dialect::Dialect;
+use crate::hid::unknown_key;
+use crate::keyboard::{keyboard, ScanCodeMatch};
+
 use indexmap::IndexMap;
 use serde_derive::{Deserialize, Serialize};
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
 use std::path::PathBuf;
 
@@ -8,8 +14,19 @@ use std::path::PathBuf;
 pub struct Animation {
     #[serde(rename = "type")]
     pub _type: Option<String>,
+    #[serde(default)]
     pub frames: Vec<String>,
+    #[serde(default)]
     pub settings: String,
+    /// Name of a `canned` animation to expand, `settings` are prepended to the canned settings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canned: Option<String>,
+    /// Values for the configurable settings of the canned animation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Kll kept along with the frames, such as the trigger of an imported canned animation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -35,7 +52,7 @@ pub struct KllHeader {
     pub other: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Led {
     pub id: usize,
     #[serde(rename = "scanCode")]
@@ -101,18 +118,433 @@ pub struct KllConfig {
     pub leds: Option<Vec<Led>>,
 }
 
+/// Bump whenever `generate_kll` emits different files for the same configuration,
+/// builds from older generators then get a new hash and are rebuilt.
+pub const GENERATOR_VERSION: u32 = 1;
+
+/// Sub-directories holding the per-half layers of a split keyboard
+pub const SPLIT_HALVES: [&str; 2] = ["left", "right"];
+
+/// Whether the file is one of the per-half copies of a split keyboard layer
+pub fn is_split_half(file: &KllFile) -> bool {
+    SPLIT_HALVES
+        .iter()
+        .any(|half| file.name.starts_with(&format!("{}/", half)))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct KllFile {
     pub content: String,
     pub name: String,
 }
 
-fn layout_matrix(filename: &str) -> Vec<MatrixKey> {
+/// A key as written in KLL, e.g. `U"A"`, `CONS"VOLUMEUP"` or `SYS"SLEEP"`
+#[derive(Clone, Debug, PartialEq)]
+pub enum KllKey {
+    Usb(String),
+    Consumer(String),
+    System(String),
+}
+
+impl KllKey {
+    /// Converts from the configurator notation (`A`, `CONS:VOLUMEUP`, `SYS:SLEEP`)
+    pub fn from_config(s: &str) -> KllKey {
+        if s.starts_with("CONS:") {
+            KllKey::Consumer(crop_str(s, 5).to_string())
+        } else if s.starts_with("SYS:") {
+            KllKey::System(crop_str(s, 4).to_string())
+        } else {
+            KllKey::Usb(s.to_string())
+        }
+    }
+
+    /// Converts back to the configurator notation
+    pub fn to_config(&self) -> String {
+        match self {
+            KllKey::Usb(k) => k.clone(),
+            KllKey::Consumer(k) => format!("CONS:{}", k),
+            KllKey::System(k) => format!("SYS:{}", k),
+        }
+    }
+}
+
+impl fmt::Display for KllKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KllKey::Usb(k) => write!(f, "U\"{}\"", k),
+            KllKey::Consumer(k) => write!(f, "CONS\"{}\"", k),
+            KllKey::System(k) => write!(f, "SYS\"{}\"", k),
+        }
+    }
+}
+
+/// The result side of a mapping, either another key or a capability such as `ledControl(0,15)`
+#[derive(Clone, Debug, PartialEq)]
+pub enum KllResult {
+    Key(KllKey),
+    Capability(String),
+}
+
+impl KllResult {
+    /// Converts from the configurator notation, capabilities are prefixed with `#:`
+    pub fn from_config(s: &str) -> KllResult {
+        if s.starts_with("#:") {
+            KllResult::Capability(crop_str(s, 2).to_string())
+        } else {
+            KllResult::Key(KllKey::from_config(s))
+        }
+    }
+}
+
+impl fmt::Display for KllResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KllResult::Key(k) => write!(f, "{}", k),
+            KllResult::Capability(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+/// `U"A" : U"B";`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KllMapping {
+    pub trigger: KllKey,
+    pub result: KllResult,
+    /// Written out as a comment, for results the firmware does not support
+    pub disabled: bool,
+}
+
+impl fmt::Display for KllMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.result, self.disabled) {
+            // Disabled capabilities keep the configurator notation
+            (KllResult::Capability(c), true) => write!(f, "#{} : #:{};", self.trigger, c),
+            (result, true) => write!(f, "#{} : {};", self.trigger, result),
+            (result, false) => write!(f, "{} : {};", self.trigger, result),
+        }
+    }
+}
+
+/// `U"A" :+ A[name](start);`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KllTrigger {
+    pub trigger: KllKey,
+    pub action: String,
+}
+
+impl fmt::Display for KllTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} :+ {};", self.trigger, self.action)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KllFrame {
+    Pixels(String),
+    Comment(String),
+}
+
+/// `A[name] <= settings;` followed by the numbered frames
+#[derive(Clone, Debug, PartialEq)]
+pub struct KllAnimation {
+    pub name: String,
+    pub settings: String,
+    pub frames: Vec<KllFrame>,
+    /// Extra statements that go along with the animation, e.g. a trigger to start it
+    pub custom: Option<String>,
+}
+
+impl KllAnimation {
+    pub fn from_config(name: &str, animation: &Animation) -> KllAnimation {
+        let frames = animation
+            .frames
+            .iter()
+            .map(|frame| {
+                if frame.starts_with('#') {
+                    KllFrame::Comment(frame.clone())
+                } else {
+                    KllFrame::Pixels(frame.clone())
+                }
+            })
+            .collect();
+
+        KllAnimation {
+            name: name.to_string(),
+            settings: animation.settings.clone(),
+            frames,
+            custom: animation.custom.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.frames.iter().any(|frame| match frame {
+            KllFrame::Pixels(_) => true,
+            KllFrame::Comment(_) => false,
+        })
+    }
+}
+
+impl fmt::Display for KllAnimation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "### {} is empty, skipping", self.name);
+        }
+
+        writeln!(f, "A[{}] <= {};", self.name, self.settings)?;
+        let mut i = 1;
+        for frame in self.frames.iter() {
+            match frame {
+                KllFrame::Pixels(p) => {
+                    writeln!(f, "A[{}, {}] <= {};", self.name, i, p)?;
+                    i += 1;
+                }
+                KllFrame::Comment(c) => writeln!(f, "{}", c)?,
+            }
+        }
+        if let Some(custom) = &self.custom {
+            writeln!(f, "{}", custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// An LED (pixel) that has been moved or remapped to a different key
+///  `P[1] : S0x01;`
+///  `P[1] <= x:0, y:0;`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KllPixel {
+    pub id: usize,
+    pub scan_code: Option<String>,
+    pub x: f32,
+    pub y: f32,
+}
+
+impl fmt::Display for KllPixel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(scan_code) = &self.scan_code {
+            writeln!(f, "P[{}] : S{};", self.id, scan_code)?;
+        }
+        write!(f, "P[{}] <= x:{}, y:{};", self.id, self.x, self.y)
+    }
+}
+
+/// A single generated .kll file.
+/// Sections set to `None` are left out entirely, only the base layer carries defines and animations.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KllDocument {
+    pub header: IndexMap<String, String>,
+    pub defines: Option<Vec<(String, String)>>,
+    pub mappings: Vec<KllMapping>,
+    pub triggers: Vec<KllTrigger>,
+    pub custom: Option<String>,
+    /// Only written out when there are changes from the base layout
+    pub pixels: Vec<KllPixel>,
+    pub animations: Option<Vec<KllAnimation>>,
+}
+
+fn join_display<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl fmt::Display for KllDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = self
+            .header
+            .iter()
+            .map(|(k, v)| format!("{} = \"{}\";", k, v))
+            .collect::<Vec<_>>()
+            .join("\n");
+        write!(f, "{}\n\n", header)?;
+
+        if let Some(defines) = &self.defines {
+            let defines = defines
+                .iter()
+                .map(|(k, v)| format!("{} = \"{}\";", k, v))
+                .collect::<Vec<_>>()
+                .join("\n");
+            write!(f, "{}\n\n", defines)?;
+        }
+
+        write!(f, "{}\n\n", join_display(&self.mappings))?;
+        write!(f, "{}", join_display(&self.triggers))?;
+        if let Some(custom) = &self.custom {
+            write!(f, "\n\n{}", custom)?;
+        }
+        write!(f, "\n\n")?;
+
+        if !self.pixels.is_empty() {
+            write!(f, "{}\n\n", join_display(&self.pixels))?;
+        }
+
+        if let Some(animations) = &self.animations {
+            write!(f, "{}\n\n", join_display(animations))?;
+        }
+        Ok(())
+    }
+}
+
+fn read_layout(filename: &str) -> Result<KllConfig, String> {
     println!("Reading {}", filename);
-    let json: KllConfig = {
-        let contents = fs::read_to_string(filename).expect("Missing layout");
-        serde_json::from_str(&contents).unwrap()
+    let contents =
+        fs::read_to_string(filename).map_err(|_| format!("Missing layout {}", filename))?;
+    serde_json::from_str(&contents).map_err(|e| format!("Invalid layout {}: {}", filename, e))
+}
+
+/// The base layout, and the dialect specific base layout the mappings are compared against.
+/// Both are the same file for most keyboards.
+pub fn base_layout_files(config: &KllConfig, target: &Dialect) -> (String, String) {
+    let name = config.header.name.replace(" ", "_");
+    let base_file = format!("./layouts/{}-{}.json", name, config.header.base);
+    let target_file = target.base_layout(&name, &config.header.base);
+    (base_file, target_file)
+}
+
+fn parse_scan_code(code: &str) -> Option<u32> {
+    let code = code.trim();
+    if code.starts_with("0x") || code.starts_with("0X") {
+        u32::from_str_radix(crop_str(code, 2), 16).ok()
+    } else {
+        code.parse().ok()
+    }
+}
+
+fn same_scan_code(a: &str, b: &str, strategy: ScanCodeMatch) -> bool {
+    match (strategy, parse_scan_code(a), parse_scan_code(b)) {
+        (ScanCodeMatch::Numeric, Some(a), Some(b)) => a == b,
+        _ => a.trim() == b.trim(),
+    }
+}
+
+fn scan_code_match(config: &KllConfig) -> ScanCodeMatch {
+    keyboard(&config.header.name).map_or(ScanCodeMatch::default(), |k| k.scan_codes)
+}
+
+/// Pairs every key of the user's matrix with a key of the base layout by scan code.
+/// Split keyboards re-use scan codes for each half, so each base key is only matched once
+///  and duplicates are paired up in order.
+fn match_base_keys(
+    matrix: &[MatrixKey],
+    default: &[MatrixKey],
+    strategy: ScanCodeMatch,
+) -> Vec<Result<usize, String>> {
+    let mut used = vec![false; default.len()];
+    matrix
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let idx = default
+                .iter()
+                .enumerate()
+                .position(|(d, def_key)| {
+                    !used[d] && same_scan_code(&key.code, &def_key.code, strategy)
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "key {} (scan code {}) is not in the base layout",
+                        i, key.code
+                    )
+                })?;
+            used[idx] = true;
+
+            if default[idx].layers.get(&0).is_none() {
+                return Err(format!(
+                    "key {} (scan code {}) has no layer 0 mapping in the base layout",
+                    i, key.code
+                ));
+            }
+            Ok(idx)
+        })
+        .collect()
+}
+
+/// The base layout key (the trigger in the generated kll) for every key of the user's matrix
+pub fn base_keys(config: &KllConfig, target: &Dialect) -> Result<Vec<Option<String>>, String> {
+    let (_, target_file) = base_layout_files(config, target);
+    let default = read_layout(&target_file)?.matrix;
+    let keys = match_base_keys(&config.matrix, &default, scan_code_match(config))
+        .into_iter()
+        .map(|matched| {
+            let idx = matched.ok()?;
+            Some(default[idx].layers.get(&0)?.key.clone())
+        })
+        .collect();
+    Ok(keys)
+}
+
+/// Every key in the matrix must correspond to a key in the base layout.
+/// The WhiteFox LTS bases predate a few of the scan codes, those keys are skipped (and
+///  logged) when generating LTS layers rather than rejected here.
+pub fn validate_matrix(config: &KllConfig) -> Result<(), String> {
+    let name = config.header.name.replace(' ', "_");
+    let base_file = format!("./layouts/{}-{}.json", name, config.header.base);
+    let default = read_layout(&base_file)?.matrix;
+
+    let errors = match_base_keys(&config.matrix, &default, scan_code_match(config))
+        .into_iter()
+        .filter_map(Result::err)
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Unknown keys: {}", errors.join(", ")))
+    }
+}
+
+/// LEDs that were added, moved or remapped compared to the base layout
+fn changed_leds(config: &KllConfig, base_leds: &[Led]) -> Vec<Led> {
+    let leds = match &config.leds {
+        Some(leds) => leds,
+        None => return Vec::new(),
     };
-    json.matrix
+
+    leds.iter()
+        .filter(|led| !base_leds.contains(led))
+        .cloned()
+        .collect()
+}
+
+/// LED ids must be unique and any LED that differs from the base layout must
+/// reference a key that exists in the matrix.
+pub fn validate_leds(config: &KllConfig) -> Result<(), String> {
+    let leds = match &config.leds {
+        Some(leds) => leds,
+        None => return Ok(()),
+    };
+
+    let mut ids = leds.iter().map(|led| led.id).collect::<Vec<_>>();
+    ids.sort();
+    if let Some(dup) = ids.windows(2).find(|w| w[0] == w[1]) {
+        return Err(format!("Duplicate LED id {}", dup[0]));
+    }
+
+    let name = config.header.name.replace(' ', "_");
+    let base = read_layout(&format!("./layouts/{}-{}.json", name, config.header.base))?;
+    let codes = config
+        .matrix
+        .iter()
+        .filter_map(|key| parse_scan_code(&key.code))
+        .collect::<Vec<_>>();
+
+    let base_leds = base.leds.unwrap_or_default();
+    for led in changed_leds(config, &base_leds) {
+        if let Some(scan_code) = &led.scan_code {
+            match parse_scan_code(scan_code) {
+                Some(code) if codes.contains(&code) => {}
+                _ => {
+                    return Err(format!(
+                        "LED {} references unknown scan code {}",
+                        led.id, scan_code
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
 fn crop_str(s: &str, pos: usize) -> &str {
@@ -141,105 +573,104 @@ pub fn kll_list(layers: Vec<String>) -> String {
 }
 
 pub fn format_key(s: &str) -> String {
-    if s.starts_with("CONS:") {
-        return format!("CONS\"{}\"", crop_str(s, 5));
-    } else if s.starts_with("SYS:") {
-        return format!("SYS\"{}\"", crop_str(s, 4));
-    } else {
-        return format!("U\"{}\"", s);
+    KllKey::from_config(s).to_string()
+}
+
+fn add_triggers(
+    triggers: &mut Vec<Vec<(String, Vec<Trigger>)>>,
+    base_key: &str,
+    ts: &IndexMap<usize, Trigger>,
+) {
+    for (t, trigger) in ts {
+        let t = *t;
+        if triggers.len() <= t {
+            triggers.resize(t + 1, Vec::new());
+        }
+
+        // Group all of the triggers for a key on the same layer together
+        match triggers[t].iter().position(|(k, _)| k == base_key) {
+            Some(idx) => triggers[t][idx].1.push(trigger.clone()),
+            None => triggers[t].push((base_key.to_string(), vec![trigger.clone()])),
+        }
     }
 }
 
-pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
+pub fn generate_kll(
+    config: &KllConfig,
+    target: &Dialect,
+    minimal: bool,
+) -> Result<Vec<KllFile>, String> {
     let header = config.header.clone();
     let name = &header.name.replace(" ", "_"); //sanitize
     let variant = header.variant.unwrap_or("".to_string()).replace(" ", "_");
     let layout = header.layout.clone();
-    let base_layout = &header.base;
 
     let mut files = Vec::new();
     if name.is_empty() || layout.is_empty() {
         // Invalid Header Information
-        return files;
+        return Ok(files);
     }
 
-    let mut default = layout_matrix(&format!("./layouts/{}-{}.json", name, base_layout));
+    let (base_file, target_file) = base_layout_files(config, target);
+    let base = read_layout(&base_file)?;
+    let base_leds = base.leds.unwrap_or_default();
+
+    // Some firmware releases use different scan codes, those have their own base layout
+    let default = if target_file == base_file {
+        base.matrix
+    } else {
+        read_layout(&target_file)?.matrix
+    };
 
     let mut layers: Vec<Vec<(String, String)>> = Vec::new();
-    let triggers: Vec<Vec<(String, Vec<Trigger>)>> = Vec::new();
+    let mut triggers: Vec<Vec<(String, Vec<Trigger>)>> = Vec::new();
 
     // Find the differences between the default map and the user's map
-    match name.to_lowercase().as_ref() {
-        // WhiteFox layouts have fewer keys than the defaultMap so we need to verify based
-        //  upon the scan codes rather than just a sequence. Long term this method should
-        //  probably be the preferred method for building up layer files
-        "whitefox" => {
-            if is_lts {
-                // Between LTS and Latest the scancode mapping for White Fox changed. Previously
-                //  there was a single all encompassing map, now there are a number of smaller
-                //  ones that have different (sensible) default scancode mappings. This causes
-                //  a little bit of havok due to the way layering works, we override what was
-                //  previously there, we'll look for a special `.lts.json` file here.
-                let lts_base_name = format!("./layouts/{}-{}.lts.json", name, base_layout);
-                default = layout_matrix(&lts_base_name);
+    let matches = match_base_keys(&config.matrix, &default, scan_code_match(config));
+    for (key, matched) in config.matrix.iter().zip(matches) {
+        let base_key = match matched {
+            Ok(idx) => &default[idx].layers.get(&0).unwrap().key,
+            Err(e) => {
+                println!("Skipping key: {}", e);
+                continue;
             }
+        };
 
-            for (_i, key) in config.matrix.iter().enumerate() {
-                // First find the corresponding key via scan code
-                let idx_in_def = default.iter().position(|def_key| key.code == def_key.code);
-
-                if let Some(idx_in_def) = idx_in_def {
-                    for (l, layer) in key.layers.iter() {
-                        let l = *l;
-                        if !layers.get(l).is_some() {
-                            layers.resize(l + 1, Vec::new());
-                        }
-                        layers[l].push((
-                            default[idx_in_def].layers.get(&0).unwrap().key.clone(),
-                            layer.key.clone(),
-                        ));
-                    }
-
-                    // Process "trigger" entries
-                    if !is_lts {
-                        if let Some(ts) = &key.triggers {
-                            for (_t, _trigger) in ts {
-                                // TODO
-                                //triggers[t][&default[idx_in_def].layers.get(&0).unwrap().key].push(trigger);
-                            }
-                        }
-                    }
-                }
+        // Process "layer" entries
+        for (l, layer) in key.layers.iter() {
+            let l = *l;
+            if layers.get(l).is_none() {
+                layers.resize(l + 1, Vec::new());
             }
+            // Leaving out an unknown key would build a different firmware than requested,
+            //  known ones are passed on to the compiler spelled as in the catalog
+            let result = match KllResult::from_config(&layer.key) {
+                KllResult::Key(k) => match k.canonical() {
+                    Some(k) => k.to_config(),
+                    None => return Err(unknown_key(&layer.key, &key.code, l)),
+                },
+                KllResult::Capability(_) => layer.key.clone(),
+            };
+            // The base layer is already the firmware's default map
+            if minimal && l == 0 && &result == base_key {
+                continue;
+            }
+            layers[l].push((base_key.clone(), result));
         }
-        _ => {
-            for (i, key) in config.matrix.iter().enumerate() {
-                // TODO: Dedup with ergodox
-                // Process "layer" entries
-                for (l, layer) in key.layers.iter() {
-                    let l = *l;
-                    if !layers.get(l).is_some() {
-                        layers.resize(l + 1, Vec::new());
-                    }
-                    layers[l].push((
-                        default[i].layers.get(&0).unwrap().key.clone(),
-                        layer.key.clone(),
-                    ));
-                }
 
-                // Process "trigger" entries
-                if !is_lts {
-                    if let Some(ts) = &key.triggers {
-                        for (_t, _trigger) in ts {
-                            // TODO
-                            //triggers[t][&default[i].layers.get(&0).unwrap().key].push(trigger);
-                        }
-                    }
-                }
+        // Process "trigger" entries
+        if target.triggers {
+            if let Some(ts) = &key.triggers {
+                add_triggers(&mut triggers, base_key, ts);
             }
         }
     }
 
+    // A layer may only contain triggers, make sure it still gets a file
+    if triggers.len() > layers.len() {
+        layers.resize(triggers.len(), Vec::new());
+    }
+
     let mut headers: IndexMap<String, String> = IndexMap::new();
     headers.insert("Name".to_string(), header.name);
     headers.insert("Variant".to_string(), variant);
@@ -251,19 +682,11 @@ pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
     headers.insert("Date".to_string(), header.date);
     headers.insert("Generator".to_string(), header.generator);
 
-    let header = headers
-        .iter()
-        .map(|(k, v)| format!("{} = \"{}\";", k, v))
-        .collect::<Vec<_>>()
-        .join("\n");
-    let defines = match &config.defines {
-        Some(d) => d
-            .iter()
-            .map(|define| format!("{} = \"{}\";", define.name, define.value))
-            .collect::<Vec<_>>()
-            .join("\n"),
-        None => "".to_string(),
-    };
+    let defines = config.defines.as_ref().map_or(Vec::new(), |d| {
+        d.iter()
+            .map(|define| (define.name.clone(), define.value.clone()))
+            .collect()
+    });
 
     //let mut file_args = Vec::new();
     let _controller_ver =
@@ -271,119 +694,103 @@ pub fn generate_kll(config: &KllConfig, is_lts: bool) -> Vec<KllFile> {
                                                                                             // let hashbaby = "";
     let layout_name = format!("{}-{}", name, layout);
 
-    let mut animations = "".to_string();
-    let mut ignored_animations = Vec::new();
-    if !is_lts {
-        match &config.animations {
-            Some(a) => {
-                animations = a
-                    .iter()
-                    .map(|(k, v)| {
-                        let mut s = format!("A[{}] <= {};\n", k, v.settings);
-
-                        let mut i = 1; // TODO: Use enumerate here
-                        for frame in v.frames.iter() {
-                            if frame.starts_with("#") {
-                                s.push_str(&format!("{}\n", frame));
-                            } else {
-                                s.push_str(&format!("A[{}, {}] <= {};\n", k, i, frame));
-                                i += 1;
-                            }
-                        }
-                        if i > 1 {
-                            return s;
-                        } else {
-                            ignored_animations.push(k);
-                            return format!("### {} is empty, skipping", k);
-                        }
-                    })
-                    .collect::<Vec<_>>()
-                    .join("\n");
+    let mut pixels = Vec::new();
+    if target.leds {
+        pixels = changed_leds(config, &base_leds)
+            .into_iter()
+            .map(|led| KllPixel {
+                id: led.id,
+                scan_code: led.scan_code,
+                x: led.x,
+                y: led.y,
+            })
+            .collect();
+    }
+
+    let mut animations = Vec::new();
+    if target.animations {
+        if let Some(a) = &config.animations {
+            for (k, v) in a.iter() {
+                match expand_canned(k, v, config) {
+                    Ok(animation) => animations.push(KllAnimation::from_config(k, &animation)),
+                    Err(e) => {
+                        // Invalid selections are rejected before building, leave a note just in case
+                        println!("Skipping animation {}: {}", k, e);
+                        animations.push(KllAnimation {
+                            name: k.clone(),
+                            settings: "".to_string(),
+                            frames: Vec::new(),
+                            custom: None,
+                        });
+                    }
+                }
             }
-            _ => {}
         }
     }
 
     // Generate .kll files
     for (n, layer) in layers.iter().enumerate() {
-        let out = layer
+        let mappings = layer
             .iter()
             .map(|(k, v)| {
-                let mut comment_out = false;
-                let k = format_key(k);
-                let mut s = v.to_string();
-                if v.starts_with("#:") {
-                    if is_lts && v.contains("ledControl") {
-                        let m = v.replace(" ", "");
-                        if m.contains("ledControl(0,15)") {
-                            // LED-
-                            s = "ledControl( 3, 15, 0 )".to_string();
-                        } else if m.contains("ledControl(1,15)") {
-                            // LED+
-                            s = "ledControl( 4, 15, 0 )".to_string();
-                        } else if m.contains("ledControl(3,0)") {
-                            // LED OFF
-                            s = "ledControl( 5, 0, 0)".to_string();
-                        } else {
-                            comment_out = true;
-                        }
-                    } else if is_lts && v.contains("animation_control") {
-                        comment_out = true;
-                    } else {
-                        s = crop_str(v, 2).to_string();
-                    }
-                } else {
-                    s = format_key(v);
-                }
-
-                if comment_out {
-                    format!("#{} : {};", k, s)
-                } else {
-                    format!("{} : {};", k, s)
-                }
+                let mut mapping = KllMapping {
+                    trigger: KllKey::from_config(k),
+                    result: KllResult::from_config(v),
+                    disabled: false,
+                };
+                target.rewrite(&mut mapping);
+                mapping
             })
-            .collect::<Vec<_>>()
-            .join("\n");
+            .collect::<Vec<_>>();
 
-        let mut triggers_out = "".to_string();
-        if let Some(triggers) = triggers.get(n) {
-            triggers_out = triggers
+        let layer_triggers = match triggers.get(n) {
+            Some(triggers) => triggers
                 .iter()
-                .map(|(k, v)| {
-                    let k = format_key(k);
-                    v.iter()
-                        .map(|t| format!("{} :+ {};", k, t.action))
-                        .collect::<Vec<_>>()
-                        .join("\n")
+                .flat_map(|(k, v)| {
+                    v.iter().map(move |t| KllTrigger {
+                        trigger: KllKey::from_config(k),
+                        action: t.action.clone(),
+                    })
                 })
-                .collect::<Vec<_>>()
-                .join("\n");
-        }
-
-        let custom = match &config.custom {
-            Some(custom) => match custom.get(&n) {
-                Some(c) => format!("\n\n{}", c),
-                None => "".to_string(),
-            },
-            None => "".to_string(),
+                .collect(),
+            None => Vec::new(),
         };
 
-        let out = {
-            if n == 0 {
-                format!(
-                    "{}\n\n{}\n\n{}\n\n{}{}\n\n{}\n\n",
-                    header, defines, out, triggers_out, custom, animations
-                )
+        let custom = config.custom.as_ref().and_then(|c| c.get(&n)).cloned();
+
+        let document = KllDocument {
+            header: headers.clone(),
+            defines: if n == 0 { Some(defines.clone()) } else { None },
+            mappings,
+            triggers: layer_triggers,
+            custom,
+            pixels: if n == 0 { pixels.clone() } else { Vec::new() },
+            animations: if n == 0 {
+                Some(animations.clone())
             } else {
-                format!("{}\n\n{}\n\n{}{}\n\n", header, out, triggers_out, custom)
-            }
+                None
+            },
         };
 
         files.push(KllFile {
-            content: out,
+            content: document.to_string(),
             name: format!("{}-{}.kll", layout_name, n),
         });
     }
 
-    return files;
+    // Each half of a split keyboard is flashed separately and gets its own set of layers
+    if keyboard(name).is_some_and(|k| k.split) {
+        let halves = SPLIT_HALVES
+            .iter()
+            .flat_map(|half| {
+                files.iter().map(move |file| KllFile {
+                    content: file.content.clone(),
+                    name: format!("{}/{}", half, file.name),
+                })
+            })
+            .collect::<Vec<_>>();
+        files.extend(halves);
+    }
+
+    Ok(files)
 }
@@ -1,10 +1,41 @@
+mod build;
+mod canned;
+mod diagnostics;
+mod dialect;
+mod firmware;
+mod gc;
+mod hid;
+mod import;
+mod jobs;
+mod keyboard;
 mod kll;
+mod package;
+mod signing;
 
 #[cfg(test)]
 mod tests {
+    use crate::build::*;
+    use crate::canned::*;
+    use crate::diagnostics::*;
+    use crate::dialect::*;
+    use crate::firmware::*;
+    use crate::gc::*;
+    use crate::hid::*;
+    use crate::import::*;
+    use crate::jobs::*;
+    use crate::keyboard::*;
     use crate::kll::*;
+    use crate::package::*;
+    use crate::signing::*;
 
+    use std::collections::HashMap;
     use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use rusqlite::Connection;
 
     use rstest::rstest_parametrize;
 
@@ -20,38 +51,39 @@ mod tests {
     }
 
     #[rstest_parametrize(
-        json_file,
+        filename,
         kll_dir,
-        case("K-Type-Standard.json", "KType-Standard"),
-        case("K-Type-NoAnimations.json", "KType-NoAnimations"),
-        case("MD1.1-Alphabet.json", "MD1.1-Alphabet"),
-        case("MD1.1-AlphabetBlank.json", "MD1.1-AlphabetBlank"),
-        case("MD1.1-Hacker.json", "MD1.1-Hacker"),
-        case("MD1.1-HackerBlank.json", "MD1.1-HackerBlank"),
-        case("MD1.1-Standard.json", "MD1.1-Standard"),
-        case("MD1.1-StandardBlank.json", "MD1.1-StandardBlank"),
-        case("MD1-Hacker.json", "MD1-Hacker"),
-        case("MD1-HackerBlank.json", "MD1-HackerBlank"),
-        case("MD1-Standard.json", "MD1-Standard"),
-        case("MD1-StandardBlank.json", "MD1-StandardBlank"),
-        case("MDErgo1-Blank.json", "MDErgo1-Blank"),
-        case("MDErgo1-Default.json", "MDErgo1-Default"),
-        case("WhiteFox-Aria.json", "WhiteFox-Aria"),
-        case("WhiteFox-Iso.json", "WhiteFox-Iso"),
-        case("WhiteFox-JackofAllTrades.json", "WhiteFox-JackofAllTrades"),
-        case("WhiteFox-TheTrueFox.json", "WhiteFox-TheTrueFox"),
-        case("WhiteFox-Vanilla.json", "WhiteFox-Vanilla"),
-        case("WhiteFox-Winkeyless.json", "WhiteFox-Winkeyless")
-    )]
-    fn generate_kll_latest(json_file: &str, kll_dir: &str) {
-        let filename = format!("{}/{}", "layouts", json_file);
+        case("layouts/K-Type-Standard.json", "KType-Standard"),
+        case("layouts/K-Type-NoAnimations.json", "KType-NoAnimations"),
+        case("layouts/MD1.1-Alphabet.json", "MD1.1-Alphabet"),
+        case("layouts/MD1.1-AlphabetBlank.json", "MD1.1-AlphabetBlank"),
+        case("layouts/MD1.1-Hacker.json", "MD1.1-Hacker"),
+        case("layouts/MD1.1-HackerBlank.json", "MD1.1-HackerBlank"),
+        case("layouts/MD1.1-Standard.json", "MD1.1-Standard"),
+        case("layouts/MD1.1-StandardBlank.json", "MD1.1-StandardBlank"),
+        case("layouts/MD1-Hacker.json", "MD1-Hacker"),
+        case("layouts/MD1-HackerBlank.json", "MD1-HackerBlank"),
+        case("layouts/MD1-Standard.json", "MD1-Standard"),
+        case("layouts/MD1-StandardBlank.json", "MD1-StandardBlank"),
+        case("layouts/MDErgo1-Blank.json", "MDErgo1-Blank"),
+        case("layouts/MDErgo1-Default.json", "MDErgo1-Default"),
+        case("layouts/WhiteFox-Aria.json", "WhiteFox-Aria"),
+        case("layouts/WhiteFox-Iso.json", "WhiteFox-Iso"),
+        case("layouts/WhiteFox-JackofAllTrades.json", "WhiteFox-JackofAllTrades"),
+        case("layouts/WhiteFox-TheTrueFox.json", "WhiteFox-TheTrueFox"),
+        case("layouts/WhiteFox-Vanilla.json", "WhiteFox-Vanilla"),
+        case("layouts/WhiteFox-Winkeyless.json", "WhiteFox-Winkeyless"),
+        case("tests/layouts/K-Type-Canned.json", "KType-Canned"),
+        case("tests/layouts/K-Type-Leds.json", "KType-Leds")
+    )]
+    fn generate_kll_latest(filename: &str, kll_dir: &str) {
         println!("Parsing {}", filename);
         let config: KllConfig = {
             let contents = fs::read_to_string(filename).unwrap();
             serde_json::from_str(&contents).unwrap()
         };
 
-        let files = generate_kll(config, false);
+        let files = generate_kll(&config, &LATEST, false).unwrap();
         for file in files {
             let kll_file = format!("{}/{}/{}", "tests/web_latest", kll_dir, file.name);
             println!("Comparing to {}", kll_file);
@@ -92,7 +124,7 @@ mod tests {
             serde_json::from_str(&contents).unwrap()
         };
 
-        let files = generate_kll(config, true);
+        let files = generate_kll(&config, &LTS, false).unwrap();
         for file in files {
             let kll_file = format!("{}/{}/{}", "tests/web_lts", kll_dir, file.name);
             println!("Comparing to {}", kll_file);
@@ -100,4 +132,1560 @@ mod tests {
             assert_eq!(file.content, kll);
         }
     }
+
+    #[rstest_parametrize(
+        json_file,
+        kll_dir,
+        case("K-Type-Triggers.json", "KType-Triggers"),
+        case("WhiteFox-Triggers.json", "WhiteFox-Triggers")
+    )]
+    fn generate_kll_triggers(json_file: &str, kll_dir: &str) {
+        let filename = format!("{}/{}", "tests/layouts", json_file);
+        println!("Parsing {}", filename);
+        let config: KllConfig = {
+            let contents = fs::read_to_string(filename).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+
+        let files = generate_kll(&config, &LATEST, false).unwrap();
+        assert!(files.iter().any(|file| file.content.contains(" :+ ")));
+        for file in files {
+            let kll_file = format!("{}/{}/{}", "tests/web_latest", kll_dir, file.name);
+            println!("Comparing to {}", kll_file);
+            let kll = fs::read_to_string(kll_file).unwrap();
+            assert_eq!(file.content, kll);
+        }
+    }
+
+    #[rstest_parametrize(
+        kll_dir,
+        case("KType-Standard"),
+        case("KType-NoAnimations"),
+        case("KType-Triggers"),
+        case("KType-Canned"),
+        case("KType-Leds"),
+        case("MD1.1-Alphabet"),
+        case("MD1.1-Hacker"),
+        case("MD1.1-StandardBlank"),
+        case("MD1-Hacker"),
+        case("MD1-Standard"),
+        case("MDErgo1-Default"),
+        case("WhiteFox-Iso"),
+        case("WhiteFox-JackofAllTrades"),
+        case("WhiteFox-Triggers")
+    )]
+    fn import_kll_roundtrip(kll_dir: &str) {
+        let dir = format!("{}/{}", "tests/web_latest", kll_dir);
+        let mut dirs = vec![dir.clone()];
+        dirs.extend(SPLIT_HALVES.iter().map(|half| format!("{}/{}", dir, half)));
+        let files = dirs
+            .iter()
+            .filter(|d| Path::new(d).is_dir())
+            .flat_map(|d| fs::read_dir(d).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "kll"))
+            .map(|path| KllFile {
+                name: path.strip_prefix(&dir).unwrap().to_str().unwrap().to_string(),
+                content: fs::read_to_string(&path).unwrap(),
+            })
+            .collect::<Vec<_>>();
+        println!("Importing {} ({} files)", dir, files.len());
+
+        let config = import_kll(&files).unwrap();
+        let generated = generate_kll(&config, &LATEST, false).unwrap();
+        assert_eq!(generated.len(), files.len());
+        for file in generated {
+            let kll_file = format!("{}/{}", dir, file.name);
+            println!("Comparing to {}", kll_file);
+            let kll = fs::read_to_string(kll_file).unwrap();
+            assert_eq!(file.content, kll);
+        }
+    }
+
+    #[rstest_parametrize(
+        json_file,
+        case("Kira-Standard.json"),
+        case("GeminiDuskDawn-Standard.json")
+    )]
+    fn import_kll_custom(json_file: &str) {
+        let filename = format!("{}/{}", "layouts", json_file);
+        println!("Parsing {}", filename);
+        let config: KllConfig = {
+            let contents = fs::read_to_string(filename).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+
+        let files = generate_kll(&config, &LATEST, false).unwrap();
+        let imported = import_kll(&files).unwrap();
+        let regenerated = generate_kll(&imported, &LATEST, false).unwrap();
+        for (file, regenerated) in files.iter().zip(regenerated) {
+            println!("Comparing {}", file.name);
+            assert_eq!(file.content, regenerated.content);
+        }
+    }
+
+    #[rstest_parametrize(
+        canned,
+        values,
+        case("miami_wave", r#"{ "speed": 7 }"#),
+        case("miami_wave", r#"{ "speed": "slow" }"#),
+        case("miami_wave", r#"{ "direction": "left" }"#),
+        case("single_color", r#"{ "color": { "r": 256, "g": 0, "b": 0 } }"#),
+        case("single_color", r#"{ "color": "red" }"#),
+        case("not_canned", "{}")
+    )]
+    fn validate_canned_invalid(canned: &str, values: &str) {
+        let mut config: KllConfig = {
+            let contents = fs::read_to_string("tests/layouts/K-Type-Canned.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        let animation = Animation {
+            _type: None,
+            frames: Vec::new(),
+            settings: "".to_string(),
+            canned: Some(canned.to_string()),
+            values: Some(serde_json::from_str(values).unwrap()),
+            custom: None,
+        };
+        config
+            .animations
+            .as_mut()
+            .unwrap()
+            .insert("invalid".to_string(), animation);
+
+        let err = validate_canned(&config).unwrap_err();
+        println!("{}", err);
+        assert!(err.starts_with("invalid: "));
+    }
+
+    #[rstest_parametrize(json_file, case("K-Type-Canned.json"), case("K-Type-Leds.json"))]
+    fn validate_test_layouts(json_file: &str) {
+        let filename = format!("{}/{}", "tests/layouts", json_file);
+        let config: KllConfig = {
+            let contents = fs::read_to_string(filename).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        validate_canned(&config).unwrap();
+        validate_leds(&config).unwrap();
+
+        // No pixel support in LTS
+        for file in generate_kll(&config, &LTS, false).unwrap() {
+            assert!(!file.content.contains("P[1]"));
+        }
+    }
+
+    #[rstest_parametrize(
+        json_file,
+        case("K-Type-Standard.json"),
+        case("Kira-Standard.json"),
+        case("GeminiDuskDawn-Standard.json")
+    )]
+    fn validate_leds_layouts(json_file: &str) {
+        let filename = format!("{}/{}", "layouts", json_file);
+        let config: KllConfig = {
+            let contents = fs::read_to_string(filename).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        validate_leds(&config).unwrap();
+    }
+
+    #[rstest_parametrize(
+        id,
+        scan_code,
+        error,
+        case(1, "0x05", "Duplicate LED id 1"),
+        case(300, "0x200", "LED 300 references unknown scan code 0x200"),
+        case(300, "ESC", "LED 300 references unknown scan code ESC")
+    )]
+    fn validate_leds_invalid(id: usize, scan_code: &str, error: &str) {
+        let mut config: KllConfig = {
+            let contents = fs::read_to_string("tests/layouts/K-Type-Leds.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        config.leds.as_mut().unwrap().push(Led {
+            id,
+            scan_code: Some(scan_code.to_string()),
+            x: 0.,
+            y: 0.,
+        });
+
+        assert_eq!(validate_leds(&config).unwrap_err(), error);
+    }
+
+    #[rstest_parametrize(
+        json_file,
+        case("K-Type-Standard.json"),
+        case("Kira-Standard.json"),
+        case("MDErgo1-Default.json"),
+        case("WhiteFox-Iso.json"),
+        case("WhiteFox-IsoBlank.json"),
+        case("GeminiDuskDawn-Standard.json")
+    )]
+    fn validate_matrix_layouts(json_file: &str) {
+        let config: KllConfig = {
+            let contents = fs::read_to_string(format!("layouts/{}", json_file)).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        validate_matrix(&config).unwrap();
+    }
+
+    #[rstest_parametrize(
+        code,
+        error,
+        case("0x7F", "Unknown keys: key 87 (scan code 0x7F) is not in the base layout"),
+        case("0x01", "Unknown keys: key 87 (scan code 0x01) is not in the base layout")
+    )]
+    fn validate_matrix_invalid(code: &str, error: &str) {
+        let mut config: KllConfig = {
+            let contents = fs::read_to_string("layouts/K-Type-Standard.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        let mut key = config.matrix[0].clone();
+        key.code = code.to_string();
+        config.matrix.push(key);
+
+        assert_eq!(validate_matrix(&config).unwrap_err(), error);
+    }
+
+    #[rstest_parametrize(
+        json_file,
+        kll_dir,
+        case("K-Type-Standard.json", "KType-Standard"),
+        case("MD1.1-Hacker.json", "MD1.1-Hacker")
+    )]
+    fn generate_kll_key_order(json_file: &str, kll_dir: &str) {
+        let mut config: KllConfig = {
+            let contents = fs::read_to_string(format!("layouts/{}", json_file)).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        // Keys are matched by scan code, so the order of the matrix doesn't matter
+        config.matrix.reverse();
+        config.matrix.sort_by(|a, b| a.code.cmp(&b.code));
+
+        let files = generate_kll(&config, &LATEST, false).unwrap();
+        for file in files {
+            let kll_file = format!("{}/{}/{}", "tests/web_latest", kll_dir, file.name);
+            let kll = fs::read_to_string(kll_file).unwrap();
+            let mut expected = kll.lines().collect::<Vec<_>>();
+            let mut actual = file.content.lines().collect::<Vec<_>>();
+            expected.sort();
+            actual.sort();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[rstest_parametrize(
+        json_file,
+        container,
+        case("K-Type-Standard.json", "controller-057"),
+        case("MDErgo1-Default.json", "controller-057"),
+        case("WhiteFox-Iso.json", "controller-057"),
+        case("WhiteFox-IsoBlank.json", "controller-050")
+    )]
+    fn generate_kll_minimal(json_file: &str, container: &str) {
+        let config: KllConfig = {
+            let contents = fs::read_to_string(format!("layouts/{}", json_file)).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+
+        let target = container_dialect(container);
+        let full = generate_kll(&config, target, false).unwrap();
+        let minimal = generate_kll(&config, target, true).unwrap();
+        assert_eq!(full.len(), minimal.len());
+        for (full, minimal) in full.iter().zip(minimal.iter()) {
+            assert_eq!(full.name, minimal.name);
+            let base_layer = full.name.ends_with("-0.kll");
+
+            // Only identity mappings on the base layer are dropped
+            let expected = full
+                .content
+                .lines()
+                .filter(|line| {
+                    let mut parts = line.trim_end_matches(';').splitn(2, " : ");
+                    let identity = match (parts.next(), parts.next()) {
+                        (Some(trigger), Some(result)) => trigger == result,
+                        _ => false,
+                    };
+                    !base_layer || !identity
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(minimal.content.lines().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[rstest_parametrize(
+        container,
+        kll,
+        case("controller-050", "0.3"),
+        case("controller-054", "0.5c"),
+        case("controller-057", "0.5c"),
+        case("controller-999", "0.5c")
+    )]
+    fn container_dialects(container: &str, kll: &str) {
+        assert_eq!(container_dialect(container).kll, kll);
+    }
+
+    #[rstest_parametrize(
+        capability,
+        expected,
+        case("ledControl(0, 15)", "U\"A\" : ledControl( 3, 15, 0 );"),
+        case("ledControl(3, 0)", "U\"A\" : ledControl( 5, 0, 0);"),
+        case("ledControl(2, 50)", "#U\"A\" : #:ledControl(2, 50);"),
+        case("animation_control(0)", "#U\"A\" : #:animation_control(0);"),
+        case("layerShift(1)", "U\"A\" : layerShift(1);")
+    )]
+    fn dialect_rewrites(capability: &str, expected: &str) {
+        let mut mapping = KllMapping {
+            trigger: KllKey::from_config("A"),
+            result: KllResult::from_config(&format!("#:{}", capability)),
+            disabled: false,
+        };
+        LTS.rewrite(&mut mapping);
+        assert_eq!(mapping.to_string(), expected);
+    }
+
+    #[rstest_parametrize(
+        json_file,
+        case("K-Type-Standard.json"),
+        case("Azio-Fokal-Standard.json"),
+        case("GeminiDuskDawn-Standard.json"),
+        case("Kira-Standard.json"),
+        case("MD1.1-Alphabet.json"),
+        case("MDErgo1-Default.json"),
+        case("WhiteFox-TheTrueFox.json")
+    )]
+    fn validate_keys_layouts(json_file: &str) {
+        let config: KllConfig = {
+            let contents = fs::read_to_string(format!("layouts/{}", json_file)).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        validate_keys(&config).unwrap();
+    }
+
+    #[rstest_parametrize(
+        key,
+        error,
+        case("LBRACKETT", "Unknown key name LBRACKETT (matrix code 0x01, layer 1)"),
+        case("CONS:VOLUME UPP", "Unknown key name CONS:VOLUME UPP (matrix code 0x01, layer 1)"),
+        case("SYS:SLEEPY", "Unknown key name SYS:SLEEPY (matrix code 0x01, layer 1)")
+    )]
+    fn validate_keys_invalid(key: &str, error: &str) {
+        let mut config: KllConfig = {
+            let contents = fs::read_to_string("layouts/K-Type-Standard.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        config.matrix[0].layers.insert(
+            1,
+            KeyAction {
+                key: key.to_string(),
+                label: None,
+            },
+        );
+        assert_eq!(validate_keys(&config).unwrap_err(), error);
+
+        // The generator refuses to build a map that differs from the request
+        assert_eq!(generate_kll(&config, &LATEST, false).err().unwrap(), error);
+    }
+
+    #[rstest_parametrize(
+        key,
+        kll,
+        case("lbrace", "U\"LBRACE\""),
+        case("Left Brace", "U\"LBRACE\""),
+        case("[", "U\"LBRACE\""),
+        case("EQUALS", "U\"EQUAL\""),
+        case("CONS:PLAY PAUSE", "CONS\"PAUSE PLAY\""),
+        case("CONS:Volume_Up", "CONS\"VOLUME UP\""),
+        case("CONS:VOLUMEUP", "CONS\"VOLUME UP\""),
+        case("SYS:Power", "SYS\"POWER DOWN\""),
+        case("SYS:wake up", "SYS\"WAKE UP\""),
+        case("U+2318", "U\"U+2318\"")
+    )]
+    fn validate_keys_aliases(key: &str, kll: &str) {
+        assert!(KllKey::from_config(key).is_known());
+
+        // Aliases are emitted as the catalog name they stand for
+        let mut config: KllConfig = {
+            let contents = fs::read_to_string("layouts/K-Type-Standard.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        config.matrix[0].layers.insert(
+            1,
+            KeyAction {
+                key: key.to_string(),
+                label: None,
+            },
+        );
+        let files = generate_kll(&config, &LATEST, false).unwrap();
+        assert!(files[1].content.contains(&format!(" : {};", kll)));
+    }
+
+    #[rstest_parametrize(
+        success,
+        artifact,
+        case(true, "MDErgo1-Default-stub.zip"),
+        case(false, "MDErgo1-Default-stub_error.zip")
+    )]
+    fn stub_backend(success: bool, artifact: &str) {
+        let config: KllConfig = {
+            let contents = fs::read_to_string("layouts/MDErgo1-Default.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        let build_dir = std::env::temp_dir().join(format!("kiisrv-stub-{}", success));
+        let _ = fs::remove_dir_all(&build_dir);
+
+        let backend = StubBackend {
+            build_dir: build_dir.clone(),
+            success,
+            delay: Duration::from_secs(0),
+        };
+        assert!(backend.environments().contains(&"controller-057".to_string()));
+
+        let info = configure_build(&config, vec!["MDErgo1-Default-0.kll".to_string()]);
+        let job = backend
+            .spawn(
+                "controller-057",
+                info,
+                "stub",
+                "MDErgo1-Default-stub.zip",
+                &build_dir.join("stub.log"),
+            )
+            .unwrap();
+        assert_eq!(job.wait().unwrap(), success);
+        let artifacts = vec![artifact.to_string(), "stub.log".to_string()];
+        assert_eq!(backend.artifacts("controller-057"), artifacts);
+        assert_eq!(fs::read(build_dir.join(artifact)).unwrap().len(), 22);
+    }
+
+    #[rstest_parametrize(json_file, case("MD1-Standard.json"), case("WhiteFox-Aria.json"))]
+    fn build_digest_canonical(json_file: &str) {
+        let contents = fs::read_to_string(format!("layouts/{}", json_file)).unwrap();
+        let config: KllConfig = serde_json::from_str(&contents).unwrap();
+        let digest = build_digest(&config, "controller-057", "v0.5.7", false).unwrap();
+        assert_eq!(digest.len(), 64);
+        assert!(digest.len() > BUILD_HASH_LEN);
+
+        // Formatting and field order in the request don't matter
+        let mut value: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        let header = value["header"].as_object().unwrap().clone();
+        let reversed: serde_json::Map<String, serde_json::Value> =
+            header.into_iter().rev().collect();
+        value["header"] = serde_json::Value::Object(reversed);
+        let reordered: KllConfig = serde_json::from_str(&value.to_string()).unwrap();
+        assert_eq!(
+            build_digest(&reordered, "controller-057", "v0.5.7", false).unwrap(),
+            digest
+        );
+
+        // Everything that changes the firmware changes the digest
+        let digest_for = |container, git_tag, minimal| {
+            build_digest(&config, container, git_tag, minimal).unwrap()
+        };
+        assert_ne!(digest_for("controller-050", "v0.5.7", false), digest);
+        assert_ne!(digest_for("controller-057", "v0.5.6", false), digest);
+        assert_ne!(digest_for("controller-057", "v0.5.7", true), digest);
+        let mut renamed = config.clone();
+        renamed.header.layout = format!("{}2", renamed.header.layout);
+        assert_ne!(
+            build_digest(&renamed, "controller-057", "v0.5.7", false).unwrap(),
+            digest
+        );
+
+        // A base layout that can't be read isn't hashed as empty
+        let mut rebased = config.clone();
+        rebased.header.base = "Missing".to_string();
+        let error = build_digest(&rebased, "controller-057", "v0.5.7", false).unwrap_err();
+        assert!(error.starts_with("Missing layout"), "{}", error);
+    }
+
+    #[test]
+    fn build_digest_keyboard_fields() {
+        let md1 = keyboard("MD1").unwrap();
+
+        // Registry fields that don't affect the build leave the digest alone
+        let mut described = md1.clone();
+        described.aliases.push("Infinity60".to_string());
+        described.flash_size = Some(1);
+        assert_eq!(described.build_inputs(), md1.build_inputs());
+
+        let mut rescripted = md1.clone();
+        rescripted.build_script = "infinity_led.bash".to_string();
+        assert_ne!(rescripted.build_inputs(), md1.build_inputs());
+        let mut split = md1.clone();
+        split.split = true;
+        assert_ne!(split.build_inputs(), md1.build_inputs());
+    }
+
+    #[rstest_parametrize(
+        name,
+        canonical,
+        split,
+        case("MD1", "MD1", false),
+        case("infinity", "MD1", false),
+        case("MDErgo1", "MDErgo1", true),
+        case("Ergodox", "MDErgo1", true),
+        case("K-Type", "KType", false),
+        case("GeminiDusk", "GeminiDuskDawn", false)
+    )]
+    fn keyboard_registry(name: &str, canonical: &str, split: bool) {
+        let keyboard = keyboard(name).unwrap();
+        assert_eq!(keyboard.name, canonical);
+        assert_eq!(keyboard.split, split);
+        assert!(keyboard.build_script.ends_with(".bash"));
+    }
+
+    #[test]
+    fn keyboard_registry_layouts() {
+        // No base layout or build script for these yet
+        let unsupported = ["Azio-Fokal-Standard.json"];
+
+        // Every layout (other than base layouts) is for a known keyboard
+        for entry in fs::read_dir("layouts").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |e| e != "json") {
+                continue;
+            }
+            let contents = fs::read_to_string(&path).unwrap();
+            let config: KllConfig = serde_json::from_str(&contents).unwrap();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert_eq!(
+                validate_keyboard(&config).is_ok(),
+                !unsupported.contains(&name),
+                "{:?}",
+                path
+            );
+        }
+
+        let config: KllConfig = {
+            let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        let mut unknown = config.clone();
+        unknown.header.name = "Model M".to_string();
+        assert_eq!(
+            validate_keyboard(&unknown),
+            Err("Unknown keyboard Model M".to_string())
+        );
+    }
+
+    #[rstest_parametrize(
+        log,
+        source,
+        file,
+        line,
+        token,
+        case(
+            "\u{1b}[1;31mERROR\u{1b}[0m: Tokenize error in /KiiConf/MD1-Standard-1.kll line 12 col 5 'U\"FOO\"'",
+            "kll",
+            "/KiiConf/MD1-Standard-1.kll",
+            12,
+            "U\"FOO\""
+        ),
+        case(
+            "MD1-Standard-0.kll:3:10: error: got unexpected token 'Name'",
+            "kll",
+            "MD1-Standard-0.kll",
+            3,
+            "Name"
+        ),
+        case(
+            "CMake Error at Lib/CMake/kll.cmake:87 (message):\n  kll compiler failed\n\n-- Configuring incomplete",
+            "cmake",
+            "Lib/CMake/kll.cmake",
+            87,
+            ""
+        ),
+        case(
+            "CMake Error: The source directory does not exist.",
+            "cmake",
+            "",
+            0,
+            ""
+        ),
+        case(
+            "generatedKeymap.h:40:2: error: 'U_FOO' undeclared",
+            "compiler",
+            "generatedKeymap.h",
+            40,
+            "U_FOO"
+        )
+    )]
+    fn parse_build_errors(log: &str, source: &str, file: &str, line: usize, token: &str) {
+        // Empty and zero stand for missing
+        let diagnostics = parse_log(log);
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.source, source);
+        assert_eq!(diagnostic.file.as_ref().map_or("", String::as_str), file);
+        assert_eq!(diagnostic.line.unwrap_or(0), line);
+        assert_eq!(diagnostic.token.as_ref().map_or("", String::as_str), token);
+    }
+
+    #[test]
+    fn parse_build_errors_ignores_noise() {
+        let log = "-- Detected Layout Files: MD1-Standard-0.kll\n\
+                   make[2]: *** [CMakeFiles/kiibohd.elf.dir/build.make:63] Error 1\n\
+                   Build succeeded";
+        assert!(parse_log(log).is_empty());
+
+        // The same error for both halves of a split keyboard is reported once
+        let log =
+            "ERROR: bad token in left/X-1.kll line 2\nERROR: bad token in left/X-1.kll line 2";
+        assert_eq!(parse_log(log).len(), 1);
+    }
+
+    #[rstest_parametrize(
+        json_file,
+        container,
+        case("MD1-Standard.json", "controller-057"),
+        case("MDErgo1-Default.json", "controller-057"),
+        case("WhiteFox-Aria.json", "controller-050")
+    )]
+    fn diagnostics_map_to_keys(json_file: &str, container: &str) {
+        let config: KllConfig = {
+            let contents = fs::read_to_string(format!("layouts/{}", json_file)).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        let dialect = container_dialect(container);
+        let files = generate_kll(&config, dialect, false).unwrap();
+
+        // Point an error at every mapping of the last layer
+        let file = files.iter().rfind(|f| !is_split_half(f)).unwrap();
+        let layer: usize = file
+            .name
+            .trim_end_matches(".kll")
+            .rsplit('-')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        for (i, text) in file.content.lines().enumerate() {
+            if !text.contains(" : ") || text.starts_with('#') {
+                continue;
+            }
+
+            let log = format!(
+                "ERROR: Tokenize error in /KiiConf/build/{} line {}",
+                file.name,
+                i + 1
+            );
+            let diagnostics = diagnose(&log, &config, dialect, &files);
+            assert_eq!(diagnostics.len(), 1);
+            let diagnostic = &diagnostics[0];
+            assert_eq!(diagnostic.file.as_ref(), Some(&file.name));
+            assert_eq!(diagnostic.text.as_deref(), Some(text));
+
+            let key = diagnostic.key.as_ref().expect(text);
+            assert_eq!(key.layer, layer);
+            let action = key.action.as_ref().unwrap();
+            let result = match KllResult::from_config(&action.key) {
+                KllResult::Key(k) => KllResult::Key(k.canonical().unwrap()),
+                result => result,
+            };
+            assert!(text.ends_with(&format!("{};", result)), "{}", text);
+            assert!(config.matrix.iter().any(|k| k.code == key.code));
+        }
+
+        // Lines that don't come from a key, and files that weren't generated
+        let log = format!(
+            "ERROR: Tokenize error in {} line 1\nERROR: bad x.kll line 1",
+            file.name
+        );
+        let diagnostics = diagnose(&log, &config, dialect, &files);
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].text.as_ref().unwrap().starts_with("Name = "));
+        assert!(diagnostics[0].key.is_none());
+        assert!(diagnostics[1].text.is_none());
+    }
+
+    #[test]
+    fn diagnostics_from_package() {
+        let contents = fs::read_to_string("layouts/MDErgo1-Default.json").unwrap();
+        let config: KllConfig = serde_json::from_str(&contents).unwrap();
+        let files = generate_kll(&config, &LATEST, false).unwrap();
+        let file = files[1].clone();
+        let lines: Vec<&str> = file.content.lines().collect();
+        let line = lines.iter().position(|l| l.contains(" : ")).unwrap() + 1;
+
+        let dir = std::env::temp_dir().join("kiisrv-diagnostics");
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("build.log");
+        fs::write(
+            &log,
+            format!(
+                "ERROR: Tokenize error in /KiiConf/build/left/{} line {}",
+                file.name, line
+            ),
+        )
+        .unwrap();
+
+        // Only the packaged layers, errors in a split half point at its layer
+        let mut package = PackageInfo {
+            keyboard: "MDErgo1".to_string(),
+            variant: "".to_string(),
+            layout: "Default".to_string(),
+            container: "controller-057".to_string(),
+            version: "v0.5.7".to_string(),
+            bcd: None,
+            config_file: "MDErgo1-Default.json".to_string(),
+            config: contents.clone(),
+            kll_files: files.into_iter().filter(|f| !is_split_half(f)).collect(),
+        };
+        let diagnostics = build_diagnostics(&log, &package);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file.as_ref(), Some(&file.name));
+        assert_eq!(diagnostics[0].line, Some(line));
+        assert!(diagnostics[0].key.is_some());
+
+        // Without a base layout the keys are left out
+        package.config = contents.replace("\"MDErgo1\"", "\"Missing\"");
+        let missing: KllConfig = serde_json::from_str(&package.config).unwrap();
+        assert!(base_keys(&missing, &LATEST).is_err());
+        let diagnostics = build_diagnostics(&log, &package);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].text.is_some());
+        assert!(diagnostics[0].key.is_none());
+
+        assert!(build_diagnostics(&dir.join("missing.log"), &package).is_empty());
+    }
+
+    #[rstest_parametrize(container_zip, case(true), case(false))]
+    fn package_artifact(container_zip: bool) {
+        let config: KllConfig = {
+            let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        let dir = std::env::temp_dir().join(format!("kiisrv-package-{}", container_zip));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let zip_path = dir.join("MD1-Standard-hash.zip");
+        let log = dir.join("hash.log");
+        fs::write(&log, "build log").unwrap();
+
+        let kll_files = generate_kll(&config, container_dialect("controller-057"), false).unwrap();
+        if container_zip {
+            // What build.sh zips up, with a stale copy of one of the layers
+            let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+            let options = zip::write::FileOptions::default();
+            for (name, data) in &[
+                ("kiibohd.dfu.bin", "firmware"),
+                ("MD1-Standard-0.kll", "stale"),
+                ("log/build.log", "container log"),
+            ] {
+                zip.start_file(*name, options).unwrap();
+                std::io::Write::write_all(&mut zip, data.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+
+        let info = PackageInfo {
+            keyboard: "MD1".to_string(),
+            variant: "standard".to_string(),
+            layout: "Standard".to_string(),
+            container: "controller-057".to_string(),
+            version: "v0.5.7".to_string(),
+            bcd: Some("0.123".to_string()),
+            config_file: "MD1-Standard.json".to_string(),
+            config: serde_json::to_string(&config).unwrap(),
+            kll_files: kll_files.clone(),
+        };
+        let checksum = package(&zip_path, &info, container_zip, &log).unwrap();
+        assert_eq!(checksum, sha256_hex(&fs::read(&zip_path).unwrap()));
+        assert!(!dir.join("MD1-Standard-hash.zip.tmp").exists());
+
+        let manifest = read_manifest(&zip_path).unwrap();
+        assert_eq!(manifest.keyboard, "MD1");
+        assert_eq!(manifest.version, "v0.5.7");
+        assert_eq!(manifest.bcd, Some("0.123".to_string()));
+        assert_eq!(manifest.success, container_zip);
+
+        let mut archive = zip::ZipArchive::new(fs::File::open(&zip_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), manifest.files.len() + 1);
+        for file in manifest.files.iter() {
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut archive.by_name(&file.name).unwrap(), &mut data)
+                .unwrap();
+            assert_eq!(file.bytes, data.len() as u64);
+            assert_eq!(file.sha256, sha256_hex(&data), "{}", file.name);
+        }
+
+        let names: Vec<&str> = manifest.files.iter().map(|f| f.name.as_str()).collect();
+        for file in kll_files.iter() {
+            assert!(!names.contains(&file.name.as_str()));
+            assert!(names.contains(&format!("kll/{}", file.name).as_str()));
+        }
+        assert!(names.contains(&"MD1-Standard.json"));
+        assert_eq!(names.contains(&"kiibohd.dfu.bin"), container_zip);
+
+        // Our kll files replace the container's, the log is only added when there was no zip
+        let mut layer = String::new();
+        std::io::Read::read_to_string(
+            &mut archive.by_name("kll/MD1-Standard-0.kll").unwrap(),
+            &mut layer,
+        )
+        .unwrap();
+        assert_eq!(layer, kll_files[0].content);
+        let mut build_log = String::new();
+        std::io::Read::read_to_string(
+            &mut archive.by_name("log/build.log").unwrap(),
+            &mut build_log,
+        )
+        .unwrap();
+        let expected = if container_zip {
+            "container log"
+        } else {
+            "build log"
+        };
+        assert_eq!(build_log, expected);
+    }
+
+    #[rstest_parametrize(key_format, case("raw"), case("hex"))]
+    fn sign_artifacts(key_format: &str) {
+        let dir = std::env::temp_dir().join(format!("kiisrv-signing-{}", key_format));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let secret: Vec<u8> = (1..=32).collect();
+        let key_file = dir.join("signing.key");
+        match key_format {
+            "raw" => fs::write(&key_file, &secret).unwrap(),
+            _ => fs::write(&key_file, format!("{}\n", to_hex(&secret))).unwrap(),
+        }
+        let signer = Signer::load(&key_file).unwrap();
+        assert_eq!(signer.public_key().len(), 64);
+
+        let zip = dir.join("MD1-Standard-hash.zip");
+        fs::write(&zip, "firmware").unwrap();
+        let sig_file = signer.sign_file(&zip).unwrap();
+        assert_eq!(sig_file, dir.join("MD1-Standard-hash.zip.sig"));
+        let signature = fs::read_to_string(&sig_file).unwrap();
+
+        let public_key = signer.public_key();
+        assert_eq!(verify(b"firmware", &signature, &public_key), Ok(()));
+        assert!(verify(b"tampered", &signature, &public_key).is_err());
+
+        let other: Vec<u8> = (2..=33).collect();
+        fs::write(&key_file, &other).unwrap();
+        let other_key = Signer::load(&key_file).unwrap().public_key();
+        assert!(verify(b"firmware", &signature, &other_key).is_err());
+        assert!(verify(b"firmware", "not hex", &public_key).is_err());
+
+        fs::write(&key_file, "too short").unwrap();
+        assert!(Signer::load(&key_file).is_err());
+    }
+
+    #[test]
+    fn check_artifact_manifest() {
+        let dir = std::env::temp_dir().join("kiisrv-manifest");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let zip_path = dir.join("MD1-Standard-hash.zip");
+
+        let info = PackageInfo {
+            keyboard: "MD1".to_string(),
+            variant: "standard".to_string(),
+            layout: "Standard".to_string(),
+            container: "controller-057".to_string(),
+            version: "v0.5.7".to_string(),
+            bcd: None,
+            config_file: "MD1-Standard.json".to_string(),
+            config: "{}".to_string(),
+            kll_files: vec![KllFile {
+                name: "MD1-Standard-0.kll".to_string(),
+                content: "U\"A\" : U\"B\";".to_string(),
+            }],
+        };
+        package(&zip_path, &info, true, &dir.join("missing.log")).unwrap();
+        assert_eq!(check_manifest(&zip_path).unwrap().files.len(), 2);
+
+        // Swap a file for one the manifest doesn't know about
+        let files: Vec<(String, Vec<u8>)> = {
+            let mut archive = zip::ZipArchive::new(fs::File::open(&zip_path).unwrap()).unwrap();
+            (0..archive.len())
+                .map(|i| {
+                    let mut file = archive.by_index(i).unwrap();
+                    let mut data = Vec::new();
+                    std::io::Read::read_to_end(&mut file, &mut data).unwrap();
+                    (file.name().to_string(), data)
+                })
+                .collect()
+        };
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        for (name, data) in files.iter() {
+            zip.start_file(name.as_str(), zip::write::FileOptions::default())
+                .unwrap();
+            let data = match name.as_str() {
+                "kll/MD1-Standard-0.kll" => b"U\"A\" : U\"C\";".to_vec(),
+                _ => data.clone(),
+            };
+            std::io::Write::write_all(&mut zip, &data).unwrap();
+        }
+        zip.finish().unwrap();
+        assert_eq!(
+            check_manifest(&zip_path).unwrap_err(),
+            "Checksum mismatch for kll/MD1-Standard-0.kll"
+        );
+    }
+
+    #[test]
+    fn native_backend_checkouts() {
+        let dir = std::env::temp_dir().join("kiisrv-native");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = dir.join("controller");
+        fs::create_dir_all(repo.join("Keyboards")).unwrap();
+        fs::write(repo.join("Keyboards/MD1.bash"), "#!/bin/bash\n").unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&repo)
+                .args([
+                    "-c",
+                    "user.name=kiisrv",
+                    "-c",
+                    "user.email=kiisrv@localhost",
+                ])
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?}", args);
+        };
+        git(&["init", "-q"]);
+        git(&["add", "-A"]);
+        git(&["commit", "-q", "-m", "Keyboards"]);
+        git(&["tag", "v0.5.7"]);
+
+        let backend = NativeBackend {
+            repo: repo.clone(),
+            tags: vec![
+                ("controller-057".to_string(), "v0.5.7".to_string()),
+                ("controller-050".to_string(), "v0.5.0".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            work_dir: dir.join("work"),
+            config_dir: dir.join("config"),
+            build_dir: dir.join("builds"),
+            checkouts: Mutex::new(HashMap::new()),
+        };
+        // Only the tags that have been fetched can be built
+        assert_eq!(backend.environments(), vec!["controller-057".to_string()]);
+
+        let checkout = backend.checkout("v0.5.7").unwrap();
+        assert!(checkout.join("Keyboards/MD1.bash").is_file());
+        assert_eq!(backend.checkout("v0.5.7").unwrap(), checkout);
+        assert!(backend.checkout("v0.5.0").is_err());
+
+        // Builds of a release that hasn't been checked out yet start at the same time
+        git(&["tag", "v0.5.8"]);
+        let backend = Arc::new(backend);
+        let checkouts: Vec<_> = (0..4)
+            .map(|_| {
+                let backend = backend.clone();
+                thread::spawn(move || backend.checkout("v0.5.8").unwrap())
+            })
+            .collect();
+        let checkouts: Vec<PathBuf> = checkouts.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(checkouts[0].join("Keyboards/MD1.bash").is_file());
+        assert!(checkouts.iter().all(|c| *c == checkouts[0]));
+
+        // A build's working directory goes once its artifact is packaged
+        let work = dir.join("work").join("builds").join("hash");
+        fs::create_dir_all(&work).unwrap();
+        backend.clean_up("hash");
+        assert!(!work.exists());
+    }
+
+    /// A 1000 byte image with a version string and a DFU suffix for `1c11:b04d`
+    fn dfu_image() -> Vec<u8> {
+        let mut data = vec![0u8; 1000];
+        let version = b"Revision: v0.5.7-3-g1a2b3c4";
+        data[100..100 + version.len()].copy_from_slice(version);
+        data.extend_from_slice(&[0x57, 0x00, 0x4d, 0xb0, 0x11, 0x1c, 0x00, 0x01]);
+        data.extend_from_slice(b"UFD");
+        data.push(16);
+        let crc = dfu_crc(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    fn write_zip(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, data) in files.iter() {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[rstest_parametrize(
+        flash_size,
+        warning,
+        case(0, false),
+        case(2000, false),
+        case(1050, true)
+    )]
+    fn firmware_metadata(flash_size: u64, warning: bool) {
+        let dir = std::env::temp_dir().join(format!("kiisrv-firmware-{}", flash_size));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let zip_path = dir.join("MD1-Standard-hash.zip");
+        let image = dfu_image();
+        write_zip(
+            &zip_path,
+            &[("kiibohd.dfu.bin", &image), ("kll.json", b"{}")],
+        );
+
+        let flash_size = Some(flash_size).filter(|size| *size > 0);
+        let info = firmware_info(&zip_path, flash_size).unwrap();
+        assert_eq!(info.images.len(), 1);
+        assert_eq!(info.flash_used, 1000);
+        assert_eq!(info.flash_warning, warning);
+        assert_eq!(
+            info.flash_usage,
+            flash_size.map(|size| 1000.0 / size as f64)
+        );
+
+        let primary = info.primary().unwrap();
+        assert_eq!(primary.name, "kiibohd.dfu.bin");
+        assert_eq!(primary.version.as_deref(), Some("v0.5.7-3-g1a2b3c4"));
+        assert_eq!(
+            primary.dfu,
+            Some(DfuSuffix {
+                vendor_id: 0x1c11,
+                product_id: 0xb04d,
+                bcd_device: 0x0057,
+                bcd_dfu: 0x0100,
+                crc_valid: true,
+            })
+        );
+    }
+
+    #[test]
+    fn firmware_metadata_invalid() {
+        let mut image = dfu_image();
+        image[0] = 0xff;
+        assert!(!DfuSuffix::parse(&image).unwrap().crc_valid);
+
+        // Without a suffix the whole file is the image
+        let image = FirmwareImage::parse("kiibohd.bin", &image[..1000]);
+        assert_eq!(image.bytes, 1000);
+        assert_eq!(image.dfu, None);
+
+        let dir = std::env::temp_dir().join("kiisrv-firmware-missing");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let zip_path = dir.join("MD1-Standard-hash_error.zip");
+        write_zip(&zip_path, &[("log/build.log", b"failed")]);
+        assert_eq!(firmware_info(&zip_path, None), None);
+        assert_eq!(firmware_info(&dir.join("missing.zip"), None), None);
+    }
+
+    /// A job table building with the stub backend in a fresh directory
+    fn stub_jobs(
+        name: &str,
+        max_running: usize,
+        delay: u64,
+    ) -> (Arc<Mutex<JobTable>>, Arc<dyn BuildBackend>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("kiisrv-jobs-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("config")).unwrap();
+        fs::create_dir_all(dir.join("logs")).unwrap();
+
+        let table = JobTable::new(max_running, &dir.join("config"), &dir.join("builds"));
+        let backend: Arc<dyn BuildBackend> = Arc::new(StubBackend {
+            build_dir: dir.join("builds"),
+            success: true,
+            delay: Duration::from_millis(delay),
+        });
+        (Arc::new(Mutex::new(table)), backend, dir)
+    }
+
+    /// Queues a build of the MD1 standard layout under the given hash
+    fn stub_submit(jobs: &Arc<Mutex<JobTable>>, hash: &str, priority: Priority) {
+        let contents = fs::read_to_string("layouts/MD1-Standard.json").unwrap();
+        let config: KllConfig = serde_json::from_str(&contents).unwrap();
+        let info = configure_build(&config, vec![format!("{}/MD1-Standard-0.kll", hash)]);
+
+        let mut table = jobs.lock().unwrap();
+        fs::create_dir_all(table.config_dir.join(hash)).unwrap();
+        let log_dir = table.config_dir.with_file_name("logs");
+        let pending = PendingBuild {
+            info,
+            log: log_file(log_dir.to_str().unwrap(), hash),
+            config: contents.clone(),
+            minimal: false,
+            package: PackageInfo {
+                keyboard: "MD1".to_string(),
+                variant: "".to_string(),
+                layout: "Standard".to_string(),
+                container: "controller-057".to_string(),
+                version: "v0.5.7".to_string(),
+                bcd: None,
+                config_file: "MD1-Standard.json".to_string(),
+                config: contents,
+                kll_files: Vec::new(),
+            },
+        };
+        let output_file = format!("MD1-Standard-{}.zip", hash);
+        table.submit(
+            Job::new(hash, "controller-057", &output_file, priority),
+            pending,
+        );
+    }
+
+    fn job_status(jobs: &Arc<Mutex<JobTable>>, hash: &str) -> Option<JobStatus> {
+        jobs.lock().unwrap().get(hash).map(|job| job.status)
+    }
+
+    /// Polls until the condition holds, the jobs are updated by other threads
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(25));
+        }
+        panic!("Timed out waiting for the jobs");
+    }
+
+    #[test]
+    fn job_queue_priorities() {
+        let (jobs, backend, _) = stub_jobs("priorities", 1, 60_000);
+        stub_submit(&jobs, "first", Priority::Normal);
+        dispatch(&jobs, &backend);
+        assert_eq!(job_status(&jobs, "first"), Some(JobStatus::Building));
+
+        stub_submit(&jobs, "normal", Priority::Normal);
+        stub_submit(&jobs, "high", Priority::High);
+        stub_submit(&jobs, "low", Priority::Low);
+        stub_submit(&jobs, "later", Priority::Normal);
+        dispatch(&jobs, &backend);
+
+        // One worker, higher priorities go first and equal ones keep their order
+        let positions = |jobs: &Arc<Mutex<JobTable>>| {
+            let table = jobs.lock().unwrap();
+            ["first", "high", "normal", "later", "low"]
+                .iter()
+                .map(|hash| table.position(hash))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(job_status(&jobs, "normal"), Some(JobStatus::Queued));
+        assert_eq!(
+            positions(&jobs),
+            vec![None, Some(0), Some(1), Some(2), Some(3)]
+        );
+
+        // Asking for a queued build again only ever raises its priority
+        jobs.lock().unwrap().raise_priority("low", Priority::High);
+        jobs.lock().unwrap().raise_priority("high", Priority::Low);
+        assert_eq!(
+            positions(&jobs),
+            vec![None, Some(0), Some(2), Some(3), Some(1)]
+        );
+
+        // The next build starts once the running one exits
+        let process = jobs.lock().unwrap().get("first").unwrap().process.clone();
+        process.unwrap().kill().unwrap();
+        wait_until(|| job_status(&jobs, "high") == Some(JobStatus::Building));
+        assert_eq!(job_status(&jobs, "first"), Some(JobStatus::Failed));
+        assert_eq!(
+            positions(&jobs),
+            vec![None, None, Some(1), Some(2), Some(0)]
+        );
+    }
+
+    #[test]
+    fn job_queue_workers() {
+        let (jobs, backend, dir) = stub_jobs("workers", 2, 100);
+        let hashes = ["a", "b", "c", "d", "e"];
+        for hash in hashes.iter() {
+            stub_submit(&jobs, hash, Priority::Normal);
+        }
+        dispatch(&jobs, &backend);
+
+        // Never more builds than workers
+        wait_until(|| {
+            let table = jobs.lock().unwrap();
+            let building = hashes
+                .iter()
+                .filter(|h| table.get(h).unwrap().status == JobStatus::Building)
+                .count();
+            assert!(building <= 2);
+            // Finished jobs have already been packaged
+            assert!(hashes.iter().all(|h| {
+                let job = table.get(h).unwrap();
+                job.status != JobStatus::Success || job.checksum.is_some()
+            }));
+            hashes
+                .iter()
+                .all(|h| table.get(h).unwrap().status == JobStatus::Success)
+        });
+
+        let table = jobs.lock().unwrap();
+        for hash in hashes.iter() {
+            let job = table.get(hash).unwrap();
+            assert!(job.started.unwrap() <= job.finished.unwrap());
+            assert!(job.checksum.is_some());
+            assert!(dir.join("builds").join(job.artifact()).exists());
+            assert_eq!(table.position(hash), None);
+        }
+    }
+
+    #[test]
+    fn job_watchdog_timeout() {
+        let (jobs, backend, dir) = stub_jobs("watchdog", 1, 60_000);
+        jobs.lock().unwrap().timeout = Some(Duration::from_millis(100));
+        stub_submit(&jobs, "slow", Priority::Normal);
+        dispatch(&jobs, &backend);
+
+        let watched = jobs.clone();
+        thread::spawn(move || watchdog(watched));
+        wait_until(|| job_status(&jobs, "slow") == Some(JobStatus::TimedOut));
+
+        let table = jobs.lock().unwrap();
+        let job = table.get("slow").unwrap();
+        assert!(job.is_stopped());
+        assert!(job.process.is_none());
+        assert!(!dir.join("config").join("slow").exists());
+    }
+
+    #[test]
+    fn job_cancel_while_starting() {
+        /// Takes a while to start each build, like the first build of a new release
+        struct SlowStart {
+            backend: Arc<dyn BuildBackend>,
+            started: Mutex<Vec<Arc<dyn BuildJob>>>,
+        }
+
+        impl BuildBackend for SlowStart {
+            fn spawn(
+                &self,
+                container: &str,
+                config: BuildInfo,
+                kll_dir: &str,
+                output_file: &str,
+                log: &Path,
+            ) -> std::io::Result<Arc<dyn BuildJob>> {
+                thread::sleep(Duration::from_millis(200));
+                let process = self
+                    .backend
+                    .spawn(container, config, kll_dir, output_file, log)?;
+                self.started.lock().unwrap().push(process.clone());
+                Ok(process)
+            }
+
+            fn environments(&self) -> Vec<String> {
+                self.backend.environments()
+            }
+
+            fn artifacts(&self, environment: &str) -> Vec<String> {
+                self.backend.artifacts(environment)
+            }
+        }
+
+        let (jobs, backend, _) = stub_jobs("starting", 1, 60_000);
+        let slow = Arc::new(SlowStart {
+            backend,
+            started: Mutex::new(Vec::new()),
+        });
+        let backend: Arc<dyn BuildBackend> = slow.clone();
+        stub_submit(&jobs, "starting", Priority::Normal);
+        stub_submit(&jobs, "queued", Priority::Normal);
+        let dispatcher = {
+            let (jobs, backend) = (jobs.clone(), backend.clone());
+            thread::spawn(move || dispatch(&jobs, &backend))
+        };
+
+        // The table isn't locked while the build starts, it can be stopped meanwhile
+        wait_until(|| job_status(&jobs, "starting") == Some(JobStatus::Building));
+        let mut table = jobs.lock().unwrap();
+        assert!(table.cancel("starting", JobStatus::Cancelled));
+        drop(table);
+        dispatcher.join().unwrap();
+
+        // The build that was started anyway is killed, its worker goes to the next job
+        let started = slow.started.lock().unwrap().clone();
+        assert_eq!(started.len(), 2);
+        assert!(!started[0].wait().unwrap());
+        assert_eq!(job_status(&jobs, "starting"), Some(JobStatus::Cancelled));
+        let process = |hash: &str| jobs.lock().unwrap().get(hash).unwrap().process.clone();
+        assert!(process("starting").is_none());
+        assert_eq!(job_status(&jobs, "queued"), Some(JobStatus::Building));
+        assert!(process("queued").is_some());
+    }
+
+    #[test]
+    fn job_cancel_token() {
+        let (jobs, backend, dir) = stub_jobs("cancel", 1, 60_000);
+        stub_submit(&jobs, "running", Priority::Normal);
+        stub_submit(&jobs, "queued", Priority::Normal);
+        dispatch(&jobs, &backend);
+
+        // Both clients that asked for the build need to give up on it
+        let (first, second) = {
+            let mut table = jobs.lock().unwrap();
+            let first = table.subscribe("running").unwrap();
+            let second = table.subscribe("running").unwrap();
+            assert_ne!(first, second);
+            (first, second)
+        };
+        let process = jobs.lock().unwrap().get("running").unwrap().process.clone();
+        let process = process.unwrap();
+
+        let mut table = jobs.lock().unwrap();
+        assert!(table.unsubscribe("running", "guessed").is_err());
+        assert_eq!(table.unsubscribe("running", &first), Ok(false));
+        assert!(table.unsubscribe("running", &first).is_err());
+        assert_eq!(table.get("running").unwrap().status, JobStatus::Building);
+
+        assert_eq!(table.unsubscribe("running", &second), Ok(true));
+        assert_eq!(table.get("running").unwrap().status, JobStatus::Cancelled);
+        assert!(!dir.join("config").join("running").exists());
+        assert!(table.unsubscribe("running", &second).is_err());
+        drop(table);
+        assert!(!process.wait().unwrap());
+
+        // A queued build is dropped from the queue and another client can start it again
+        let token = jobs.lock().unwrap().subscribe("queued").unwrap();
+        assert_eq!(jobs.lock().unwrap().unsubscribe("queued", &token), Ok(true));
+        assert_eq!(jobs.lock().unwrap().position("queued"), None);
+        assert!(jobs.lock().unwrap().get("queued").unwrap().is_stopped());
+    }
+
+    #[test]
+    fn job_waiter_disconnect() {
+        let (jobs, backend, _) = stub_jobs("disconnect", 1, 60_000);
+        jobs.lock().unwrap().cancel_on_disconnect = true;
+        stub_submit(&jobs, "shared", Priority::Normal);
+        dispatch(&jobs, &backend);
+
+        // The build keeps running while any client is still waiting on it
+        let first = waiter(&jobs, "shared");
+        let second = waiter(&jobs, "shared");
+        let token = jobs.lock().unwrap().subscribe("shared").unwrap();
+        drop(first);
+        assert_eq!(job_status(&jobs, "shared"), Some(JobStatus::Building));
+        drop(second);
+        assert_eq!(job_status(&jobs, "shared"), Some(JobStatus::Building));
+
+        // Once the last one has gone the build is stopped
+        let last = waiter(&jobs, "shared");
+        assert_eq!(
+            jobs.lock().unwrap().unsubscribe("shared", &token),
+            Ok(false)
+        );
+        drop(last);
+        assert_eq!(job_status(&jobs, "shared"), Some(JobStatus::Cancelled));
+    }
+
+    #[test]
+    fn job_table_restore() {
+        let (jobs, backend, dir) = stub_jobs("restore", 1, 0);
+        let db_path = dir.join("jobs.db");
+        let db = Connection::open(&db_path).unwrap();
+        db.execute_batch(include_str!("../schema/jobs.sqlite"))
+            .unwrap();
+        assert!(jobs.lock().unwrap().open(db).is_empty());
+
+        stub_submit(&jobs, "done", Priority::Normal);
+        dispatch(&jobs, &backend);
+        wait_until(|| job_status(&jobs, "done") == Some(JobStatus::Success));
+        let done = jobs.lock().unwrap().get("done").unwrap().clone();
+        jobs.lock().unwrap().downloaded(&done.artifact());
+
+        // Not dispatched, still queued when the server stops
+        stub_submit(&jobs, "queued", Priority::Low);
+        jobs.lock()
+            .unwrap()
+            .raise_priority("queued", Priority::High);
+
+        let mut table = JobTable::new(1, &dir.join("config"), &dir.join("builds"));
+        let mut stored = table.open(Connection::open(&db_path).unwrap());
+        stored.sort_by(|a, b| a.job.hash.cmp(&b.job.hash));
+        assert_eq!(stored.len(), 2);
+
+        let restored = &stored[0].job;
+        assert_eq!(restored.hash, "done");
+        assert_eq!(restored.container, "controller-057");
+        assert_eq!(restored.output_file, done.output_file);
+        assert_eq!(restored.status, JobStatus::Success);
+        assert_eq!(restored.priority, Priority::Normal);
+        assert_eq!(restored.created, done.created);
+        assert_eq!(restored.started, done.started);
+        assert_eq!(restored.finished, done.finished);
+        assert!(restored.downloaded.is_some());
+        assert_eq!(restored.checksum, done.checksum);
+        assert!(restored.process.is_none());
+        assert!(stored[0].config.contains("MD1"));
+        assert!(!stored[0].minimal);
+
+        let queued = &stored[1].job;
+        assert_eq!(queued.hash, "queued");
+        assert_eq!(queued.status, JobStatus::Queued);
+        assert_eq!(queued.priority, Priority::High);
+        assert!(queued.started.is_none() && queued.finished.is_none());
+        assert_eq!(
+            table.stored_config("queued"),
+            Some((stored[1].config.clone(), false))
+        );
+
+        // Forgotten jobs are gone from the database as well
+        table.forget("done");
+        assert_eq!(table.stored_config("done"), None);
+    }
+
+    #[test]
+    fn gc_least_recently_used() {
+        let (jobs, backend, dir) = stub_jobs("gc", 3, 0);
+        for hash in ["old", "recent", "new"].iter() {
+            stub_submit(&jobs, hash, Priority::Normal);
+        }
+        dispatch(&jobs, &backend);
+        wait_until(|| {
+            ["old", "recent", "new"]
+                .iter()
+                .all(|h| job_status(&jobs, h) == Some(JobStatus::Success))
+        });
+
+        let builds = dir.join("builds");
+        fs::write(builds.join("unknown.zip"), "not built by a job").unwrap();
+        let artifact = |hash: &str| jobs.lock().unwrap().get(hash).unwrap().artifact();
+        let (old, recent) = (artifact("old"), artifact("recent"));
+        let signature = signature_file(&builds.join(&old));
+        fs::write(&signature, "signed").unwrap();
+        let size = |file: &str| fs::metadata(builds.join(file)).unwrap().len();
+        let total: u64 = ["old", "recent", "new"]
+            .iter()
+            .map(|h| size(&artifact(h)))
+            .sum::<u64>()
+            + size("unknown.zip");
+        // The zip's signature is counted, and removed, along with it
+        let signature_size = fs::metadata(&signature).unwrap().len();
+        let total = total + signature_size;
+        let (old_size, recent_size) = (size(&old) + signature_size, size(&recent));
+        {
+            let mut table = jobs.lock().unwrap();
+            let now = chrono::Utc::now();
+            table.jobs.get_mut("old").unwrap().downloaded = Some(now - chrono::Duration::days(2));
+            table.jobs.get_mut("recent").unwrap().downloaded =
+                Some(now - chrono::Duration::minutes(30));
+        }
+        let log = dir.join("logs").join("old.log");
+        fs::write(&log, "").unwrap();
+
+        // Only the zip downloaded before the age limit, nothing is removed on a dry run
+        let policy = GcPolicy {
+            max_bytes: None,
+            max_age: Some(chrono::Duration::hours(1)),
+            build_dir: builds.clone(),
+            log_dir: dir.join("logs"),
+        };
+        let report = collect(&jobs, &policy, true);
+        assert!(report.dry_run);
+        let removed: Vec<(&str, &str)> = report
+            .removed
+            .iter()
+            .map(|r| (r.file.as_str(), r.reason))
+            .collect();
+        assert_eq!(removed, vec![(old.as_str(), "age")]);
+        assert_eq!(report.removed[0].hash, Some("old".to_string()));
+        assert_eq!(report.freed_bytes, old_size);
+        assert_eq!(report.remaining_bytes, total - old_size);
+        assert!(builds.join(&old).exists());
+        assert!(job_status(&jobs, "old").is_some());
+
+        // Least recently used first until the rest fits
+        let policy = GcPolicy {
+            max_bytes: Some(total - old_size - 1),
+            max_age: None,
+            ..policy
+        };
+        let report = collect(&jobs, &policy, false);
+        let removed: Vec<(&str, &str)> = report
+            .removed
+            .iter()
+            .map(|r| (r.file.as_str(), r.reason))
+            .collect();
+        assert_eq!(
+            removed,
+            vec![(old.as_str(), "size"), (recent.as_str(), "size")]
+        );
+        assert_eq!(report.remaining_bytes, total - old_size - recent_size);
+
+        // Removed jobs are forgotten along with their logs and kll files
+        assert!(!builds.join(&old).exists() && !builds.join(&recent).exists());
+        assert!(!signature.exists());
+        assert!(builds.join(artifact("new")).exists());
+        assert!(builds.join("unknown.zip").exists());
+        assert_eq!(job_status(&jobs, "old"), None);
+        assert_eq!(job_status(&jobs, "recent"), None);
+        assert_eq!(job_status(&jobs, "new"), Some(JobStatus::Success));
+        assert!(!log.exists());
+        assert!(!dir.join("config").join("new").exists());
+    }
+
+    #[test]
+    fn job_groups_expire() {
+        let (jobs, backend, dir) = stub_jobs("groups", 2, 0);
+        let db_path = dir.join("jobs.db");
+        let db = Connection::open(&db_path).unwrap();
+        db.execute_batch(include_str!("../schema/jobs.sqlite"))
+            .unwrap();
+        jobs.lock().unwrap().open(db);
+
+        stub_submit(&jobs, "first", Priority::Normal);
+        stub_submit(&jobs, "second", Priority::Normal);
+        dispatch(&jobs, &backend);
+        wait_until(|| {
+            job_status(&jobs, "first") == Some(JobStatus::Success)
+                && job_status(&jobs, "second") == Some(JobStatus::Success)
+        });
+        let members = vec![
+            ("v1".to_string(), "first".to_string()),
+            ("v2".to_string(), "second".to_string()),
+        ];
+        jobs.lock().unwrap().register_group("both", members.clone());
+        let missing = vec![("v1".to_string(), "missing".to_string())];
+        jobs.lock().unwrap().register_group("missing", missing);
+
+        // Saved next to the jobs
+        let reopened = |expect: Option<&Vec<(String, String)>>| {
+            let mut table = JobTable::new(1, &dir.join("config"), &dir.join("builds"));
+            table.open(Connection::open(&db_path).unwrap());
+            assert_eq!(table.group("both"), expect);
+        };
+        reopened(Some(&members));
+
+        // Kept while any of its jobs is still known
+        let first = jobs.lock().unwrap().get("first").unwrap().clone();
+        {
+            let mut table = jobs.lock().unwrap();
+            let downloaded = chrono::Utc::now() - chrono::Duration::days(2);
+            table.jobs.get_mut("first").unwrap().downloaded = Some(downloaded);
+        }
+        let policy = GcPolicy {
+            max_bytes: None,
+            max_age: Some(chrono::Duration::days(1)),
+            build_dir: dir.join("builds"),
+            log_dir: dir.join("logs"),
+        };
+        let report = collect(&jobs, &policy, false);
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(report.removed[0].file, first.artifact());
+        assert_eq!(jobs.lock().unwrap().group("both"), Some(&members));
+        assert_eq!(jobs.lock().unwrap().group("missing"), None);
+        reopened(Some(&members));
+
+        // Forgotten along with the last one
+        let policy = GcPolicy {
+            max_age: Some(chrono::Duration::zero()),
+            ..policy
+        };
+        collect(&jobs, &policy, false);
+        assert_eq!(job_status(&jobs, "second"), None);
+        assert_eq!(jobs.lock().unwrap().group("both"), None);
+        reopened(None);
+    }
 }
@@ -1,23 +1,47 @@
 mod build;
+mod canned;
+mod diagnostics;
+mod dialect;
+mod firmware;
+mod gc;
+mod hid;
+mod import;
+mod jobs;
+mod keyboard;
 mod kll;
+mod package;
+mod signing;
 mod versions;
 
 use crate::build::*;
+use crate::canned::validate_canned;
+use crate::diagnostics::*;
+use crate::dialect::container_dialect;
+use crate::firmware::FirmwareInfo;
+use crate::gc::*;
+use crate::hid::validate_keys;
+use crate::import::*;
+use crate::jobs::*;
+use crate::keyboard::{keyboards, validate_keyboard};
 use crate::kll::*;
+use crate::package::{check_manifest, PackageInfo};
+use crate::signing::*;
 //use crate::versions::version_map;
 
 use indexmap::IndexMap;
-use std::collections::hash_map::{DefaultHasher, HashMap};
+use std::collections::hash_map::HashMap;
 use std::fs;
-use std::hash::{Hash, Hasher};
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use bodyparser;
 use iron::prelude::*;
-use iron::{headers, modifiers::Header, status, typemap::Key};
+use iron::response::WriteBody;
+use iron::{headers, modifiers::Header, status, typemap::Key, Handler};
 use logger::Logger;
 use mount::Mount;
 use persistent::{Read, Write};
@@ -29,8 +53,8 @@ use chrono::prelude::*;
 use rusqlite::{types::ToSql, Connection};
 
 use serde_derive::{Deserialize, Serialize};
-use serde_json;
-use shared_child::SharedChild;
+use serde_json::{self, json};
+use sha2::{Digest, Sha256};
 
 const MAX_BODY_LENGTH: usize = 1024 * 1024 * 10;
 const BUILD_ROUTE: &str = "./tmp";
@@ -38,38 +62,106 @@ const BUILD_ROUTE: &str = "./tmp";
 const LAYOUT_DIR: &str = "./layouts";
 const BUILD_DIR: &str = "./tmp_builds";
 const CONFIG_DIR: &str = "./tmp_config";
+const LOG_DIR: &str = "./tmp_logs";
+
+/// Seconds between garbage collection runs when KIISRV_GC_INTERVAL isn't set
+const DEFAULT_GC_INTERVAL: u64 = 60 * 60;
+
+/// Concurrent builds when KIISRV_MAX_BUILDS isn't set, further builds wait in the queue
+const DEFAULT_MAX_BUILDS: usize = 4;
+
+/// Seconds a build may run when KIISRV_BUILD_TIMEOUT isn't set, 0 disables the timeout
+const DEFAULT_BUILD_TIMEOUT: u64 = 15 * 60;
 
 const STATS_DB_FILE: &str = "./stats.db";
 const STATS_DB_SCHEMA: &str = include_str!("../schema/stats.sqlite");
 
+const JOBS_DB_FILE: &str = "./jobs.db";
+const JOBS_DB_SCHEMA: &str = include_str!("../schema/jobs.sqlite");
+
 const CONFIG_DB_FILE: &str = "./config.db";
 const CONFIG_DB_SCHEMA: &str = include_str!("../schema/config.sqlite");
 
 const CONTROLLER_GIT_URL: &str = "https://github.com/kiibohd/controller.git";
 const CONTROLLER_GIT_REMOTE: &str = "controller";
 
+/// A single firmware version, or a list of them to build the same config against each
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BuildEnv {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl BuildEnv {
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            BuildEnv::One(env) => vec![env.clone()],
+            BuildEnv::Many(envs) => envs.clone(),
+        }
+    }
+
+    pub fn is_list(&self) -> bool {
+        match self {
+            BuildEnv::One(_) => false,
+            BuildEnv::Many(_) => true,
+        }
+    }
+}
+
 #[derive(Clone, Deserialize)]
 pub struct BuildRequest {
     pub config: KllConfig,
-    pub env: String,
+    pub env: BuildEnv,
+    /// Only emit the base layer mappings that differ from the base layout
+    #[serde(default)]
+    pub minimal: bool,
+    /// Queue priority, defaults to high for the desktop configurator and normal for the web
+    #[serde(default)]
+    pub priority: Option<Priority>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ImportRequest {
+    pub files: Vec<KllFile>,
 }
 
 #[derive(Clone, Serialize)]
 pub struct BuildResult {
     pub filename: String,
     pub success: bool,
-}
-
-#[derive(Clone)]
-pub enum JobEntry {
-    Building(Arc<SharedChild>),
-    Finished(bool),
+    /// SHA-256 of the zip
+    pub sha256: Option<String>,
+    /// Detached Ed25519 signature of the zip, when signing is enabled
+    pub signature: Option<String>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Size, USB ids, version and flash usage of the firmware, for successful builds
+    pub firmware: Option<FirmwareInfo>,
 }
 
 #[derive(Copy, Clone)]
 pub struct JobQueue;
 impl Key for JobQueue {
-    type Value = HashMap<String, JobEntry>;
+    type Value = JobTable;
+}
+
+#[derive(Copy, Clone)]
+pub struct Backend;
+impl Key for Backend {
+    type Value = Arc<dyn BuildBackend>;
+}
+
+/// Set when artifacts are signed
+#[derive(Copy, Clone)]
+pub struct Signing;
+impl Key for Signing {
+    type Value = Option<Arc<Signer>>;
+}
+
+#[derive(Copy, Clone)]
+pub struct Gc;
+impl Key for Gc {
+    type Value = GcPolicy;
 }
 
 #[derive(Copy, Clone)]
@@ -100,6 +192,13 @@ struct RequestLog {
     success: bool,
     request_time: DateTime<Utc>,
     build_duration: Option<i32>,
+    digest: Option<String>,
+    firmware_bytes: Option<i64>,
+    flash_size: Option<i64>,
+    firmware_version: Option<String>,
+    usb_vid: Option<i32>,
+    usb_pid: Option<i32>,
+    bcd_device: Option<i32>,
 }
 impl RequestLog {
     fn from_row(row: &rusqlite::Row) -> Self {
@@ -118,6 +217,13 @@ impl RequestLog {
             success: row.get(11),
             request_time: row.get(12),
             build_duration: row.get(13),
+            digest: row.get(14),
+            firmware_bytes: row.get(15),
+            flash_size: row.get(16),
+            firmware_version: row.get(17),
+            usb_vid: row.get(18),
+            usb_pid: row.get(19),
+            bcd_device: row.get(20),
         }
     }
 }
@@ -171,207 +277,928 @@ fn get_layout(req: &mut Request<'_, '_>) -> IronResult<Response> {
     )))
 }
 
-fn build_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
-    if let Ok(Some(body)) = req.get::<bodyparser::Struct<BuildRequest>>() {
-        let ip = req.remote_addr.ip();
-        let user_agent = req
-            .headers
-            .get::<headers::UserAgent>()
-            .unwrap_or(&iron::headers::UserAgent("".to_owned()))
-            .to_string();
-
-        let os = {
-            let ua = user_agent.to_lowercase();
-            if ua.contains("windows") {
-                "Windows"
-            } else if ua.contains("mac") {
-                "Mac"
-            } else if ua.contains("linux") || ua.contains("x11") {
-                "Linux"
+/// Everything needed to start (or look up) a build, shared by the sync and async endpoints
+struct PreparedBuild {
+    /// Requested firmware version (`lts`, `latest`, `v0.5.6`, ...)
+    env: String,
+    config: KllConfig,
+    config_str: String,
+    container: String,
+    minimal: bool,
+    priority: Priority,
+    hash: String,
+    /// Full build digest, `hash` is a prefix of it
+    digest: String,
+    ip: String,
+    os: String,
+    is_desktop_configurator: bool,
+    request_time: DateTime<Utc>,
+}
+
+fn error_response(e: &str) -> Response {
+    Response::with((
+        status::BadRequest,
+        Header(headers::ContentType::json()),
+        serde_json::to_string(&json!({ "error": e })).unwrap(),
+    ))
+}
+
+/// Parses and validates a build request, returning a build for every requested version
+/// (deduplicated) and whether a list of versions was requested
+fn prepare_build(req: &mut Request<'_, '_>) -> Result<(Vec<PreparedBuild>, bool), Response> {
+    let body = match req.get::<bodyparser::Struct<BuildRequest>>() {
+        Ok(Some(body)) => body,
+        Ok(None) => return Err(error_response("bad request")),
+        Err(err) => {
+            println!("Parse error: {:?}", err);
+            use bodyparser::BodyErrorCause::JsonError;
+            let s = if let JsonError(e) = err.cause {
+                println!("e: {:?}", e);
+                e.to_string()
             } else {
-                "Unknown"
-            }
+                err.detail
+            };
+            return Err(error_response(&s));
         }
+    };
+
+    let ip = req.remote_addr.ip();
+    let user_agent = req
+        .headers
+        .get::<headers::UserAgent>()
+        .unwrap_or(&iron::headers::UserAgent("".to_owned()))
         .to_string();
 
-        let is_desktop_configurator = user_agent.to_lowercase().contains("electron");
-        println!("IP: {:?}", ip);
-        println!("OS: {:?}", os);
-        println!("WEB: {:?}", !is_desktop_configurator);
+    let os = {
+        let ua = user_agent.to_lowercase();
+        if ua.contains("windows") {
+            "Windows"
+        } else if ua.contains("mac") {
+            "Mac"
+        } else if ua.contains("linux") || ua.contains("x11") {
+            "Linux"
+        } else {
+            "Unknown"
+        }
+    }
+    .to_string();
+
+    let is_desktop_configurator = user_agent.to_lowercase().contains("electron");
+    println!("IP: {:?}", ip);
+    println!("OS: {:?}", os);
+    println!("WEB: {:?}", !is_desktop_configurator);
+
+    let config = body.config;
+    let valid = validate_keyboard(&config)
+        .and_then(|_| validate_matrix(&config))
+        .and_then(|_| validate_keys(&config))
+        .and_then(|_| validate_canned(&config))
+        .and_then(|_| validate_leds(&config));
+    if let Err(e) = valid {
+        println!("Invalid config: {}", e);
+        return Err(error_response(&e));
+    }
+
+    let envs = body.env.names();
+    if envs.is_empty() {
+        return Err(error_response("No firmware versions requested"));
+    }
 
-        let request_time: DateTime<Utc> = Utc::now();
+    let config_str = serde_json::to_string(&config).unwrap();
 
-        let config = body.config;
-	//let versions = req.get::<Read<VersionsMap>>().unwrap();
-        //let container = versions.get(body.env).unwrap_or("controller-050");
-        let container = match body.env.as_ref() {
-            "lts" => "controller-050",
-            "nightly" => "controller-057",
-            "latest" | _ => "controller-057",
-        }
-        .to_string();
+    let request_time: DateTime<Utc> = Utc::now();
 
-        let config_str = serde_json::to_string(&config).unwrap();
+    let priority = body.priority.unwrap_or(match is_desktop_configurator {
+        true => Priority::High,
+        false => Priority::Normal,
+    });
 
-        let request_time: DateTime<Utc> = Utc::now();
+    let versions = req.get::<Read<Versions>>().unwrap();
+    let mut builds = Vec::new();
+    for env in envs {
+        if builds.iter().any(|b: &PreparedBuild| b.env == env) {
+            continue;
+        }
 
-        let hash = {
-            let mut hasher = DefaultHasher::new();
-            container.hash(&mut hasher);
-            config_str.hash(&mut hasher);
-            let h = hasher.finish();
-            format!("{:x}", h)
+        let container = match versions.get(&env) {
+            Some(version) => version.container.clone(),
+            None => match env.as_ref() {
+                "lts" => "controller-050",
+                "nightly" => "controller-057",
+                "latest" | _ => "controller-057",
+            }
+            .to_string(),
         };
-        println!("Received request: {}", hash);
-
-        let info = configure_build(&config, vec!["".to_string()]);
-        let mut output_file = format!("{}-{}-{}.zip", info.name, info.layout, hash);
-        //let file_exists = Path::new(&output_file).exists();
-        //println!("Zip exists: {:?} ({})", file_exists, output_file)
-
-        let job: JobEntry = {
-            let mutex = req.get::<Write<JobQueue>>().expect("Could not find mutex");
-            let queue = mutex.lock(); //.expect("Could not lock mutex"); // *** Panics if poisoned **
-            if let Err(e) = queue {
-                eprintln!("{:?}", e);
-                std::process::exit(1);
+
+        let git_tag =
+            release(&versions, &env, &container).map_or(String::new(), |v| v.git_tag.clone());
+
+        let digest = match build_digest(&config, &container, &git_tag, body.minimal) {
+            Ok(digest) => digest,
+            Err(e) => {
+                println!("Invalid config: {}", e);
+                return Err(error_response(&e));
             }
-            let mut queue = queue.unwrap();
-            let job = (*queue).get(&hash);
+        };
+        let hash = digest[..BUILD_HASH_LEN].to_string();
+        println!("Received request: {} ({})", hash, env);
+
+        builds.push(PreparedBuild {
+            env,
+            config: config.clone(),
+            config_str: config_str.clone(),
+            container,
+            minimal: body.minimal,
+            priority,
+            hash,
+            digest,
+            ip: ip.to_string(),
+            os: os.clone(),
+            is_desktop_configurator,
+            request_time,
+        });
+    }
 
-            //if file_exists && job.is_some() {
-            if job.is_some() {
-                println!(" > Existing task");
-                job.unwrap().clone()
-            } else {
-                println!(" > Starting new build in container {}", container);
+    Ok((builds, body.env.is_list()))
+}
 
-                let config_dir = format!("{}/{}", CONFIG_DIR, hash);
-                fs::create_dir_all(&config_dir).expect("Could not create directory");
+/// The controller release a container was built from
+fn release<'a>(
+    versions: &'a HashMap<String, VersionInfo>,
+    env: &str,
+    container: &str,
+) -> Option<&'a VersionInfo> {
+    versions
+        .get(env)
+        .filter(|v| v.container == container)
+        .or_else(|| versions.values().find(|v| v.container == container))
+}
 
-                let mut layers: Vec<String> = Vec::new();
-                let files = generate_kll(&config, body.env == "lts");
-                for file in files {
-                    let filename = format!("{}/{}", config_dir, file.name);
-                    fs::write(&filename, file.content).expect("Could not write kll file");
-                    layers.push(format!("{}", filename));
-                }
+/// Returns the existing job for the hash, or writes out the kll files and queues a new build
+fn start_job(req: &mut Request<'_, '_>, build: &PreparedBuild) -> Result<Job, String> {
+    let job = queue_job(req, build)?;
+
+    let backend = req.get::<Read<Backend>>().unwrap();
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    dispatch(&queue, &backend);
 
-		println!("{:?}", layers);
-                let info = configure_build(&config, layers);
-                let output_file = format!("{}-{}-{}.zip", info.name, info.layout, hash);
-                println!("{:?}", info);
+    let queue = queue.lock().expect("Could not lock mutex");
+    Ok((*queue).get(&job.hash).cloned().unwrap_or(job))
+}
 
-                let config_file = format!("{}/{}-{}.json", config_dir, info.name, info.layout);
-                fs::write(&config_file, &config_str).expect("Could not write config file");
+fn queue_job(req: &mut Request<'_, '_>, build: &PreparedBuild) -> Result<Job, String> {
+    let config = &build.config;
+    let hash = &build.hash;
+    let container = &build.container;
 
-                let process = start_build(container.clone(), info, hash.clone(), output_file);
-                let job = JobEntry::Building(Arc::new(process));
-                (*queue).insert(hash.clone(), job.clone());
-                job
-            }
+    let info = configure_build(config, vec!["".to_string()]);
+    let output_file = format!("{}-{}-{}.zip", info.name, info.layout, hash);
 
-            // drop lock
-        };
+    let mutex = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let queue = mutex.lock(); //.expect("Could not lock mutex"); // *** Panics if poisoned **
+    if let Err(e) = queue {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+    let mut queue = queue.unwrap();
+
+    match (*queue).get(hash) {
+        Some(job) if job.is_stopped() => println!(" > Restarting {} task", job.status.name()),
+        Some(job) => {
+            println!(" > Existing task");
+            let job = job.clone();
+            (*queue).raise_priority(hash, build.priority);
+            return Ok(job);
+        }
+        None => {}
+    }
 
-        let (success, duration) = match job {
-            JobEntry::Building(arc) => {
-                let process = arc.clone();
-                println!(" > Waiting for task to finish {}", process.id());
-                let exit_status = process.wait().unwrap();
-                let success: bool = exit_status.success();
-                println!(" > Done");
-
-                {
-                    let rwlock = req.get::<Write<JobQueue>>().expect("Could not find mutex");
-                    let mut queue = rwlock.lock().expect("Could not lock mutex");
-                    let job = (*queue).get_mut(&hash).expect("Could not find job");
-                    *job = JobEntry::Finished(success);
-                    // drop lock
-                }
+    println!(" > Queueing new build for container {}", container);
 
-                let duration = Some(Utc::now().signed_duration_since(request_time));
-                (success, duration)
+    let versions = req.get::<Read<Versions>>().unwrap();
+    let (info, package) = write_build_files(
+        config,
+        &build.config_str,
+        container,
+        build.minimal,
+        hash,
+        release(&versions, &build.env, container),
+    )?;
+    let job = Job::new(hash, container, &output_file, build.priority);
+    let pending = PendingBuild {
+        info,
+        log: log_file(LOG_DIR, hash),
+        config: build.config_str.clone(),
+        minimal: build.minimal,
+        package,
+    };
+    (*queue).submit(job.clone(), pending);
+    Ok(job)
+    // drop lock
+}
+
+/// Writes out the kll files and config json for a build into `CONFIG_DIR/<hash>`,
+/// returns them along with what is needed to package the zip once built
+fn write_build_files(
+    config: &KllConfig,
+    config_str: &str,
+    container: &str,
+    minimal: bool,
+    hash: &str,
+    release: Option<&VersionInfo>,
+) -> Result<(BuildInfo, PackageInfo), String> {
+    let config_dir = format!("{}/{}", CONFIG_DIR, hash);
+    fs::create_dir_all(&config_dir).expect("Could not create directory");
+
+    let mut layers: Vec<String> = Vec::new();
+    let files = generate_kll(config, container_dialect(container), minimal)?;
+    for file in files.iter() {
+        let filename = format!("{}/{}", config_dir, file.name);
+        let dir = Path::new(&filename).parent().unwrap();
+        fs::create_dir_all(dir).expect("Could not create directory");
+        fs::write(&filename, &file.content).expect("Could not write kll file");
+
+        // Per-half copies share the layer names of the top level files
+        if !is_split_half(file) {
+            layers.push(format!("{}", filename));
+        }
+    }
+
+    println!("{:?}", layers);
+    let info = configure_build(config, layers);
+    println!("{:?}", info);
+
+    let config_file = format!("{}-{}.json", info.name, info.layout);
+    fs::write(format!("{}/{}", config_dir, config_file), config_str)
+        .expect("Could not write config file");
+
+    let package = PackageInfo {
+        keyboard: info.name.clone(),
+        variant: info.variant.clone(),
+        layout: info.layout.clone(),
+        container: container.to_string(),
+        version: release.map_or(String::new(), |v| v.git_tag.clone()),
+        bcd: release.and_then(|v| v.info.as_ref()).map(|i| i.bcd.clone()),
+        config_file,
+        config: config_str.to_string(),
+        kll_files: files.into_iter().filter(|f| !is_split_half(f)).collect(),
+    };
+    Ok((info, package))
+}
+
+/// Loads the jobs saved by a previous run. Finished builds are kept as long as their zip
+/// still exists and builds that were interrupted by the restart are queued again.
+fn restore_jobs(queue: &mut JobTable, db: Connection, versions: &HashMap<String, VersionInfo>) {
+    println!("\nExisting builds: ");
+    for stored in queue.open(db) {
+        let mut job = stored.job;
+        let hash = job.hash.clone();
+
+        if job.status.is_finished() {
+            let artifact = Path::new(BUILD_DIR).join(job.artifact());
+            if job.is_stopped() || artifact.exists() {
+                println!(" - {} ({})", hash, job.status.name());
+                queue.restore(job);
+            } else {
+                println!(" - {} (missing {:?})", hash, artifact);
+                queue.forget(&hash);
             }
-            JobEntry::Finished(success) => {
-                println!(" > Job already in finished {}. Updating time.", hash);
-                (success, None)
+            continue;
+        }
+
+        let (config_str, minimal) = (&stored.config, stored.minimal);
+        let files = serde_json::from_str::<KllConfig>(config_str)
+            .map_err(|e| e.to_string())
+            .and_then(|config| validate_keyboard(&config).map(|_| config))
+            .and_then(|config| {
+                write_build_files(
+                    &config,
+                    config_str,
+                    &job.container,
+                    minimal,
+                    &hash,
+                    release(versions, "", &job.container),
+                )
+            });
+        match files {
+            Ok((info, package)) => {
+                println!(" - {} (interrupted, queued again)", hash);
+                job.status = JobStatus::Queued;
+                job.started = None;
+                let pending = PendingBuild {
+                    info,
+                    log: log_file(LOG_DIR, &hash),
+                    config: stored.config,
+                    minimal: stored.minimal,
+                    package,
+                };
+                queue.submit(job, pending);
             }
-        };
+            Err(e) => {
+                println!(" - {} (interrupted, {})", hash, e);
+                job.finish(false);
+                queue.restore(job);
+            }
+        }
+    }
 
-        let build_duration = match duration {
-            Some(t) => Some(t.num_milliseconds()),
-            None => None,
-        };
-        println!(
-            "Started at: {:?}, Duration: {:?}",
-            request_time, build_duration
-        );
+    // Groups of builds that were forgotten above
+    for id in queue.expire_groups() {
+        println!(" - group {} (expired)", id);
+    }
+}
 
-        let layers = vec![""];
-        let args: &[&ToSql] = &[
-            &(ip.to_string()),
-            &os,
-            &!is_desktop_configurator,
-            &hash,
-            &info.name,
-            &info.layout,
-            &(layers.len() as u32),
-            &container,
-            &success,
-            &request_time,
-            &build_duration,
-        ];
-
-        {
-            let mutex = req
-                .get::<Write<StatsDatabase>>()
-                .expect("Could not find mutex");
-            let db = mutex.lock().expect("Could not lock mutex");
-            // TODO: uid, serial
-            (*db).execute("INSERT INTO Requests (ip_addr, os, web, hash, board, variant, layers, container, success, request_time, build_duration)
-                  VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", args).unwrap_or_else(|_| {
-			println!("Error: Failed to insert request into stats db");
-			0 as usize
-		});
+fn log_request(db: &Mutex<Connection>, build: &PreparedBuild, job: &Job, waited: bool) {
+    let success = job.status == JobStatus::Success;
+    let build_duration = match waited {
+        true => Some(
+            Utc::now()
+                .signed_duration_since(build.request_time)
+                .num_milliseconds(),
+        ),
+        false => {
+            println!(" > Job already in finished {}. Updating time.", build.hash);
+            None
         }
+    };
+    println!(
+        "Started at: {:?}, Duration: {:?}",
+        build.request_time, build_duration
+    );
+
+    let info = configure_build(&build.config, vec!["".to_string()]);
+    let layers = vec![""];
+    let firmware = job.firmware.as_ref();
+    let primary = firmware.and_then(|f| f.primary());
+    let dfu = primary.and_then(|image| image.dfu.as_ref());
+    let args: &[&ToSql] = &[
+        &build.ip,
+        &build.os,
+        &!build.is_desktop_configurator,
+        &build.hash,
+        &info.name,
+        &info.layout,
+        &(layers.len() as u32),
+        &build.container,
+        &success,
+        &build.request_time,
+        &build_duration,
+        &build.digest,
+        &firmware.map(|f| f.flash_used as i64),
+        &firmware.and_then(|f| f.flash_size).map(|size| size as i64),
+        &primary.and_then(|image| image.version.clone()),
+        &dfu.map(|d| d.vendor_id as i32),
+        &dfu.map(|d| d.product_id as i32),
+        &dfu.map(|d| d.bcd_device as i32),
+    ];
+
+    let db = db.lock().expect("Could not lock mutex");
+    // TODO: uid, serial
+    (*db).execute("INSERT INTO Requests (ip_addr, os, web, hash, board, variant, layers, container, success, request_time, build_duration, digest, firmware_bytes, flash_size, firmware_version, usb_vid, usb_pid, bcd_device)
+          VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", args).unwrap_or_else(|_| {
+        println!("Error: Failed to insert request into stats db");
+        0 as usize
+    });
+}
 
-        if !success {
-            output_file = format!("{}-{}-{}_error.zip", info.name, info.layout, hash);
+/// Where the signature of a finished job's zip can be downloaded, if it was signed
+fn signature_url(job: &Job) -> Option<String> {
+    if !job.status.is_finished() {
+        return None;
+    }
+    let artifact = Path::new(BUILD_DIR).join(job.artifact());
+    signature_file(&artifact).file_name().and_then(|name| {
+        Path::new(BUILD_DIR)
+            .join(name)
+            .exists()
+            .then(|| format!("{}/{}", BUILD_ROUTE, name.to_string_lossy()))
+    })
+}
+
+fn job_json(job: &Job, queue: &JobTable) -> serde_json::Value {
+    // Stopped builds don't produce a zip
+    let filename = match job.status {
+        JobStatus::Success | JobStatus::Failed => {
+            Some(format!("{}/{}", BUILD_ROUTE, job.artifact()))
         }
+        _ => None,
+    };
+    json!({
+        "id": job.hash,
+        "status": job.status,
+        "priority": job.priority,
+        "queue_position": queue.position(&job.hash),
+        "container": job.container,
+        "created": job.created,
+        "started": job.started,
+        "finished": job.finished,
+        "filename": filename,
+        "sha256": job.checksum,
+        "signature": signature_url(job),
+        "log": format!("/jobs/{}/log", job.hash),
+        "diagnostics": job.diagnostics,
+        "firmware": job.firmware,
+    })
+}
 
-        let result = BuildResult {
-            filename: format!("{}/{}", BUILD_ROUTE, output_file),
-            success: success,
-        };
+/// Synchronous build, the response is sent once the firmware has been built
+fn build_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let build = match prepare_build(req) {
+        Ok((builds, false)) => builds.into_iter().next().unwrap(),
+        Ok((builds, true)) => return build_group_request(req, builds),
+        Err(response) => return Ok(response),
+    };
 
+    let job = match start_job(req, &build) {
+        Ok(job) => job,
+        Err(e) => return Ok(error_response(&e)),
+    };
+    let waited = !job.status.is_finished();
+
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let db = req
+        .get::<Write<StatsDatabase>>()
+        .expect("Could not find mutex");
+    let waiter = waiter(&queue, &build.hash);
+
+    let cancel_on_disconnect = queue
+        .lock()
+        .expect("Could not lock mutex")
+        .cancel_on_disconnect;
+    if cancel_on_disconnect && waited {
+        let result: Box<dyn WriteBody> = Box::new(PendingResult {
+            queue,
+            db,
+            build,
+            job,
+            _waiter: waiter,
+        });
         return Ok(Response::with((
             status::Ok,
             Header(headers::ContentType::json()),
-            serde_json::to_string(&result).unwrap(),
+            result,
         )));
-    } else if let Err(err) = req.get::<bodyparser::Struct<BuildRequest>>() {
-        println!("Parse error: {:?}", err);
-        use bodyparser::BodyErrorCause::JsonError;
-        let s = if let JsonError(e) = err.cause {
-            println!("e: {:?}", e);
-            e.to_string()
+    }
+
+    let job = wait_for_job(&queue, &build.hash).unwrap_or(job);
+    drop(waiter);
+    Ok(Response::with((
+        status::Ok,
+        Header(headers::ContentType::json()),
+        build_result(&db, &build, &job, waited),
+    )))
+}
+
+/// Logs a finished build and returns the `BuildResult` json
+fn build_result(db: &Mutex<Connection>, build: &PreparedBuild, job: &Job, waited: bool) -> String {
+    log_request(db, build, job, waited);
+
+    let result = BuildResult {
+        filename: format!("{}/{}", BUILD_ROUTE, job.artifact()),
+        success: job.status == JobStatus::Success,
+        sha256: job.checksum.clone(),
+        signature: signature_url(job),
+        diagnostics: job.diagnostics.clone(),
+        firmware: job.firmware.clone(),
+    };
+    serde_json::to_string(&result).unwrap()
+}
+
+/// Synchronous build result that notices when the client disconnects.
+/// Leading whitespace is valid json, so a space is sent every poll until the build finishes.
+struct PendingResult {
+    queue: Arc<Mutex<JobTable>>,
+    db: Arc<Mutex<Connection>>,
+    build: PreparedBuild,
+    job: Job,
+    _waiter: Waiter,
+}
+
+impl WriteBody for PendingResult {
+    fn write_body(&mut self, res: &mut dyn std::io::Write) -> std::io::Result<()> {
+        loop {
+            let job = {
+                let queue = self.queue.lock().expect("Could not lock mutex");
+                (*queue).get(&self.build.hash).cloned()
+                // drop lock
+            };
+            let job = job.unwrap_or_else(|| self.job.clone());
+            if job.status.is_finished() {
+                let result = build_result(&self.db, &self.build, &job, true);
+                return res.write_all(result.as_bytes());
+            }
+
+            res.write_all(b" ")?;
+            res.flush()?;
+            thread::sleep(Duration::from_millis(500));
+        }
+    }
+}
+
+/// Synchronous build of several versions, responds with the combined status once all
+/// of them have finished
+fn build_group_request(
+    req: &mut Request<'_, '_>,
+    builds: Vec<PreparedBuild>,
+) -> IronResult<Response> {
+    let jobs: Vec<Job> = match builds.iter().map(|build| start_job(req, build)).collect() {
+        Ok(jobs) => jobs,
+        Err(e) => return Ok(error_response(&e)),
+    };
+    let id = register_group(req, &builds);
+
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let db = req
+        .get::<Write<StatsDatabase>>()
+        .expect("Could not find mutex");
+    for (build, job) in builds.iter().zip(jobs) {
+        let waited = !job.status.is_finished();
+        let job = wait_for_job(&queue, &build.hash).unwrap_or(job);
+        log_request(&db, build, &job, waited);
+    }
+
+    let members: Vec<(String, String)> = builds.into_iter().map(|b| (b.env, b.hash)).collect();
+    let queue = queue.lock().expect("Could not lock mutex");
+    Ok(Response::with((
+        status::Ok,
+        Header(headers::ContentType::json()),
+        serde_json::to_string(&group_json(&queue, &id, &members)).unwrap(),
+    )))
+}
+
+/// Starts (or joins) a build and returns immediately, poll `/jobs/:id` for the result.
+/// With a list of versions every version gets its own job, poll `/groups/:id` instead.
+fn build_async_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let (builds, is_list) = match prepare_build(req) {
+        Ok(prepared) => prepared,
+        Err(response) => return Ok(response),
+    };
+
+    let jobs: Vec<Job> = match builds.iter().map(|build| start_job(req, build)).collect() {
+        Ok(jobs) => jobs,
+        Err(e) => return Ok(error_response(&e)),
+    };
+    let group = match is_list {
+        true => Some(register_group(req, &builds)),
+        false => None,
+    };
+
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let db = req
+        .get::<Write<StatsDatabase>>()
+        .expect("Could not find mutex");
+
+    let members: Vec<(String, String)> = builds
+        .iter()
+        .map(|b| (b.env.clone(), b.hash.clone()))
+        .collect();
+    for (build, job) in builds.into_iter().zip(jobs.iter()) {
+        if job.status.is_finished() {
+            log_request(&db, &build, job, false);
         } else {
-            err.detail
-        };
+            let queue = queue.clone();
+            let db = db.clone();
+            thread::spawn(move || {
+                if let Some(job) = wait_for_job(&queue, &build.hash) {
+                    log_request(&db, &build, &job, true);
+                }
+            });
+        }
+    }
 
+    // Only the clients holding a token may cancel the build, and only once nobody else wants it
+    let mut table = queue.lock().expect("Could not lock mutex");
+    let tokens: Vec<Option<String>> = members
+        .iter()
+        .map(|(_, hash)| table.subscribe(hash))
+        .collect();
+    let response = match group {
+        Some(id) => {
+            let mut response = group_json(&table, &id, &members);
+            let versions = response["versions"].as_array_mut().unwrap();
+            for (version, token) in versions.iter_mut().zip(tokens) {
+                version["cancel_token"] = json!(token);
+            }
+            response
+        }
+        None => {
+            let mut response = job_json(&jobs[0], &table);
+            response["cancel_token"] = json!(tokens[0]);
+            response
+        }
+    };
+    Ok(Response::with((
+        status::Accepted,
+        Header(headers::ContentType::json()),
+        serde_json::to_string(&response).unwrap(),
+    )))
+}
+
+/// Remembers which job was started for each requested version, returns the group id
+fn register_group(req: &mut Request<'_, '_>, builds: &[PreparedBuild]) -> String {
+    let members: Vec<(String, String)> = builds
+        .iter()
+        .map(|b| (b.env.clone(), b.hash.clone()))
+        .collect();
+
+    let mut hasher = Sha256::new();
+    for (env, hash) in members.iter() {
+        hasher.update(format!("{}:{};", env, hash).as_bytes());
+    }
+    let id = format!("{:x}", hasher.finalize())[..BUILD_HASH_LEN].to_string();
+
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let mut queue = queue.lock().expect("Could not lock mutex");
+    (*queue).register_group(&id, members);
+    id
+}
+
+/// Per-version results of a group, `success` is null until that version has finished
+fn group_json(queue: &JobTable, id: &str, members: &[(String, String)]) -> serde_json::Value {
+    let jobs: Vec<Option<&Job>> = members.iter().map(|(_, hash)| queue.get(hash)).collect();
+
+    let status = if jobs
+        .iter()
+        .any(|job| job.is_some_and(|j| !j.status.is_finished()))
+    {
+        "building"
+    } else if jobs
+        .iter()
+        .all(|job| job.is_some_and(|j| j.status == JobStatus::Success))
+    {
+        "success"
+    } else {
+        "failed"
+    };
+
+    let versions: Vec<serde_json::Value> = members
+        .iter()
+        .zip(jobs)
+        .map(|((env, hash), job)| match job {
+            Some(job) => {
+                let mut row = job_json(job, queue);
+                row["version"] = json!(env);
+                row["success"] = match job.status.is_finished() {
+                    true => json!(job.status == JobStatus::Success),
+                    false => serde_json::Value::Null,
+                };
+                row
+            }
+            None => json!({ "version": env, "id": hash, "status": "unknown", "success": false }),
+        })
+        .collect();
+
+    json!({
+        "id": id,
+        "status": status,
+        "versions": versions,
+    })
+}
+
+fn group_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let id = job_id(req);
+
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let queue = queue.lock().expect("Could not lock mutex");
+    let members = match (*queue).group(&id) {
+        Some(members) => members,
+        None => {
+            return Ok(Response::with((
+                status::NotFound,
+                Header(headers::ContentType::json()),
+                "{ \"error\": \"unknown group\" }",
+            )))
+        }
+    };
+
+    Ok(Response::with((
+        status::Ok,
+        Header(headers::ContentType::json()),
+        serde_json::to_string(&group_json(&queue, &id, members)).unwrap(),
+    )))
+}
+
+fn job_id(req: &Request<'_, '_>) -> String {
+    req.extensions
+        .get::<Router>()
+        .unwrap()
+        .find("id")
+        .unwrap_or("")
+        .to_string()
+}
+
+fn unknown_job() -> Response {
+    Response::with((
+        status::NotFound,
+        Header(headers::ContentType::json()),
+        "{ \"error\": \"unknown job\" }",
+    ))
+}
+
+fn job_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let id = job_id(req);
+
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let queue = queue.lock().expect("Could not lock mutex");
+    match (*queue).get(&id) {
+        Some(job) => Ok(Response::with((
+            status::Ok,
+            Header(headers::ContentType::json()),
+            serde_json::to_string(&job_json(job, &queue)).unwrap(),
+        ))),
+        None => Ok(unknown_job()),
+    }
+}
+
+/// Stops a queued or running build, requires the `token` returned when the build was
+/// requested. A build shared with other clients keeps running until none of them want it.
+fn job_cancel_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let id = job_id(req);
+    let token = req
+        .get::<UrlEncodedQuery>()
+        .ok()
+        .and_then(|params| params.get("token").and_then(|t| t.first().cloned()))
+        .unwrap_or_default();
+
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let mut queue = queue.lock().expect("Could not lock mutex");
+    match (*queue).get(&id) {
+        None => return Ok(unknown_job()),
+        Some(job) if job.status.is_finished() => {
+            return Ok(Response::with((
+                status::Conflict,
+                Header(headers::ContentType::json()),
+                "{ \"error\": \"job already finished\" }",
+            )));
+        }
+        Some(_) => {}
+    }
+
+    if let Err(e) = (*queue).unsubscribe(&id, &token) {
         return Ok(Response::with((
-            status::BadRequest,
+            status::Forbidden,
             Header(headers::ContentType::json()),
-            format!("{{ \"error\": \"{}\" }}", s),
+            serde_json::to_string(&json!({ "error": e })).unwrap(),
         )));
     }
 
-    return Ok(Response::with((
-        status::BadRequest,
+    let job = (*queue).get(&id).unwrap();
+    Ok(Response::with((
+        status::Ok,
+        Header(headers::ContentType::json()),
+        serde_json::to_string(&job_json(job, &queue)).unwrap(),
+    )))
+}
+
+/// The complete build log, available while building and after the job has finished
+fn job_log_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let id = job_id(req);
+    match fs::read(log_file(LOG_DIR, &id)) {
+        Ok(log) => Ok(Response::with((
+            status::Ok,
+            Header(headers::ContentType::plaintext()),
+            log,
+        ))),
+        Err(_) => Ok(unknown_job()),
+    }
+}
+
+/// Live build log as Server-Sent Events
+fn job_events_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let id = job_id(req);
+    let jobs = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    if jobs
+        .lock()
+        .expect("Could not lock mutex")
+        .get(&id)
+        .is_none()
+    {
+        return Ok(unknown_job());
+    }
+
+    let stream: Box<dyn WriteBody> = Box::new(LogStream {
+        _waiter: waiter(&jobs, &id),
+        jobs,
+        path: log_file(LOG_DIR, &id),
+        hash: id,
+    });
+    Ok(Response::with((
+        status::Ok,
+        Header(headers::ContentType("text/event-stream".parse().unwrap())),
+        Header(headers::CacheControl(vec![
+            headers::CacheDirective::NoCache,
+        ])),
+        stream,
+    )))
+}
+
+/// Serves a build zip, recording the download so recently used zips are kept by the GC
+fn download_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let file = req.url.path().last().map(|file| file.to_string());
+    if let Some(file) = file {
+        let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+        let mut queue = queue.lock().expect("Could not lock mutex");
+        (*queue).downloaded(&file);
+    }
+    Static::new(Path::new(BUILD_DIR)).handle(req)
+}
+
+/// Runs the garbage collector now, `?dry_run=1` only reports what would be removed.
+/// Requires `Authorization: Bearer $KIISRV_ADMIN_TOKEN`.
+fn admin_gc_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    let token = std::env::var("KIISRV_ADMIN_TOKEN").unwrap_or_default();
+    let authorized = match req.headers.get::<headers::Authorization<headers::Bearer>>() {
+        Some(auth) => !token.is_empty() && auth.token == token,
+        None => false,
+    };
+    if !authorized {
+        return Ok(Response::with((
+            status::Forbidden,
+            Header(headers::ContentType::json()),
+            "{ \"error\": \"forbidden\" }",
+        )));
+    }
+
+    let dry_run = match req.get::<UrlEncodedQuery>() {
+        Ok(params) => params
+            .get("dry_run")
+            .is_some_and(|v| v.iter().any(|v| v != "0" && v != "false")),
+        Err(_) => false,
+    };
+
+    let policy = req.get::<Read<Gc>>().unwrap();
+    let queue = req.get::<Write<JobQueue>>().expect("Could not find mutex");
+    let report = collect(&queue, &policy, dry_run);
+    Ok(Response::with((
+        status::Ok,
+        Header(headers::ContentType::json()),
+        serde_json::to_string(&report).unwrap(),
+    )))
+}
+
+/// The key that verifies artifact signatures, the signed message is the SHA-256 of the zip
+fn signing_key_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    match req.get::<Read<Signing>>().unwrap().as_ref() {
+        Some(signer) => Ok(Response::with((
+            status::Ok,
+            Header(headers::ContentType::json()),
+            serde_json::to_string(&json!({
+                "algorithm": "ed25519",
+                "public_key": signer.public_key(),
+                "signed": "sha256",
+            }))
+            .unwrap(),
+        ))),
+        None => Ok(Response::with((
+            status::NotFound,
+            Header(headers::ContentType::json()),
+            "{ \"error\": \"signing disabled\" }",
+        ))),
+    }
+}
+
+fn keyboards_request(_req: &mut Request<'_, '_>) -> IronResult<Response> {
+    Ok(Response::with((
+        status::Ok,
         Header(headers::ContentType::json()),
-        "{ \"error\": \"bad request\" }",
-    )));
+        serde_json::to_string(keyboards()).unwrap(),
+    )))
+}
+
+fn import_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
+    match req.get::<bodyparser::Struct<ImportRequest>>() {
+        Ok(Some(body)) => {
+            println!("Importing {} kll files", body.files.len());
+            match import_kll(&body.files) {
+                Ok(config) => Ok(Response::with((
+                    status::Ok,
+                    Header(headers::ContentType::json()),
+                    serde_json::to_string(&config).unwrap(),
+                ))),
+                Err(e) => {
+                    println!("Import error: {}", e);
+                    Ok(Response::with((
+                        status::BadRequest,
+                        Header(headers::ContentType::json()),
+                        serde_json::to_string(&json!({ "error": e })).unwrap(),
+                    )))
+                }
+            }
+        }
+        Ok(None) => Ok(Response::with((
+            status::BadRequest,
+            Header(headers::ContentType::json()),
+            "{ \"error\": \"bad request\" }",
+        ))),
+        Err(err) => {
+            println!("Parse error: {:?}", err);
+            Ok(Response::with((
+                status::BadRequest,
+                Header(headers::ContentType::json()),
+                serde_json::to_string(&json!({ "error": err.detail })).unwrap(),
+            )))
+        }
+    }
 }
 
 fn stats(req: &mut Request<'_, '_>) -> IronResult<Response> {
@@ -479,7 +1306,10 @@ fn versions_request(req: &mut Request<'_, '_>) -> IronResult<Response> {
     )))
 }
 
-fn version_map(db: rusqlite::Connection) -> HashMap<String, VersionInfo> {
+fn version_map(
+    db: rusqlite::Connection,
+    backend: &dyn BuildBackend,
+) -> HashMap<String, VersionInfo> {
     let args: &[&ToSql] = &[];
     let mut stmt = db.prepare("SELECT * FROM Versions").unwrap();
     let rows = stmt
@@ -487,7 +1317,7 @@ fn version_map(db: rusqlite::Connection) -> HashMap<String, VersionInfo> {
         .unwrap();
     let mut versions: Vec<VersionMap> = rows.map(|r| r.unwrap()).collect();
 
-    let containers = list_containers();
+    let containers = backend.environments();
     let tags = fetch_tags();
     versions
         .into_iter()
@@ -499,6 +1329,7 @@ fn version_map(db: rusqlite::Connection) -> HashMap<String, VersionInfo> {
                     container: v.container,
                     channel: v.channel,
                     info: tags.get(&v.git_tag).map(|v| v.clone()),
+                    git_tag: v.git_tag,
                 },
             )
         })
@@ -571,6 +1402,7 @@ pub struct VersionInfo {
     container: String,
     channel: String,
     info: Option<ReleaseInfo>,
+    git_tag: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -582,7 +1414,136 @@ pub struct ReleaseInfo {
     notes: String,
 }
 
+/// Artifact budgets from KIISRV_GC_MAX_MB and KIISRV_GC_MAX_AGE_DAYS, both unlimited by default
+fn gc_policy() -> GcPolicy {
+    let env = |name| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+    GcPolicy {
+        max_bytes: env("KIISRV_GC_MAX_MB").map(|mb| mb * 1024 * 1024),
+        max_age: env("KIISRV_GC_MAX_AGE_DAYS").map(|days| chrono::Duration::days(days as i64)),
+        build_dir: PathBuf::from(BUILD_DIR),
+        log_dir: PathBuf::from(LOG_DIR),
+    }
+}
+
+/// Release tag of each container, from the Versions table
+fn release_tags(db: &rusqlite::Connection) -> HashMap<String, String> {
+    let args: &[&ToSql] = &[];
+    let mut stmt = db.prepare("SELECT * FROM Versions").unwrap();
+    let rows = stmt.query_map(args, VersionMap::from_row).unwrap();
+    rows.map(|r| r.unwrap())
+        .map(|v| (v.container, v.git_tag))
+        .collect()
+}
+
+/// Selected with KIISRV_BACKEND: `docker-compose` (default), `local`, `native` or `stub`
+fn build_backend(config_db: &rusqlite::Connection) -> Arc<dyn BuildBackend> {
+    let backend = std::env::var("KIISRV_BACKEND");
+    let backend = backend.as_ref().map_or("docker-compose", String::as_str);
+    println!("Build backend: {}", backend);
+
+    match backend {
+        "local" => {
+            let root = std::env::var("KIISRV_CONTROLLER_ROOT");
+            let root = root.as_ref().map_or("./controllers", String::as_str);
+            Arc::new(LocalBackend {
+                root: PathBuf::from(root),
+                config_dir: PathBuf::from(CONFIG_DIR),
+                build_dir: PathBuf::from(BUILD_DIR),
+            })
+        }
+        "native" => {
+            let repo = std::env::var("KIISRV_CONTROLLER_REPO");
+            let repo = repo.as_ref().map_or("./controller", String::as_str);
+            let work_dir = std::env::var("KIISRV_NATIVE_DIR");
+            let work_dir = work_dir.as_ref().map_or("./tmp_native", String::as_str);
+            Arc::new(NativeBackend {
+                repo: PathBuf::from(repo),
+                tags: release_tags(config_db),
+                work_dir: PathBuf::from(work_dir),
+                config_dir: PathBuf::from(CONFIG_DIR),
+                build_dir: PathBuf::from(BUILD_DIR),
+                checkouts: Mutex::new(HashMap::new()),
+            })
+        }
+        "stub" => Arc::new(StubBackend {
+            build_dir: PathBuf::from(BUILD_DIR),
+            success: true,
+            delay: Duration::from_secs(0),
+        }),
+        _ => Arc::new(DockerComposeBackend),
+    }
+}
+
+/// `kiisrv verify <zip> [--key <public key>] [--signature <file>]`
+/// Checks the files in the zip against its manifest, and the signature when given a key.
+/// The key is the hex public key or a file containing it.
+fn verify_command(args: &[String]) -> i32 {
+    let mut zip = None;
+    let mut key = std::env::var("KIISRV_PUBLIC_KEY").ok();
+    let mut signature = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => key = args.next().cloned(),
+            "--signature" => signature = args.next().map(PathBuf::from),
+            _ => zip = Some(PathBuf::from(arg)),
+        }
+    }
+    let zip = match zip {
+        Some(zip) => zip,
+        None => {
+            eprintln!("Usage: kiisrv verify <zip> [--key <public key>] [--signature <file>]");
+            return 2;
+        }
+    };
+
+    let manifest = match check_manifest(&zip) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("FAILED {:?}: {}", zip, e);
+            return 1;
+        }
+    };
+    println!(
+        "Manifest OK: {} {} {} ({} files)",
+        manifest.keyboard,
+        manifest.layout,
+        manifest.version,
+        manifest.files.len()
+    );
+
+    let key = match key {
+        Some(key) => fs::read_to_string(&key).unwrap_or(key),
+        None => {
+            println!("No public key given, signature not checked");
+            return 0;
+        }
+    };
+    let signature = signature.unwrap_or_else(|| signature_file(&zip));
+    let result = fs::read_to_string(&signature)
+        .map_err(|e| format!("Could not read {:?}: {}", signature, e))
+        .and_then(|sig| {
+            let data = fs::read(&zip).map_err(|e| e.to_string())?;
+            verify(&data, &sig, &key)
+        });
+    match result {
+        Ok(()) => {
+            println!("Signature OK");
+            0
+        }
+        Err(e) => {
+            println!("FAILED {:?}: {}", zip, e);
+            1
+        }
+    }
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("verify") {
+        std::process::exit(verify_command(&args[2..]));
+    }
+
     pretty_env_logger::init();
 
     let result = Command::new("git")
@@ -604,7 +1565,31 @@ fn main() {
         std::process::exit(status.code().unwrap_or(1));
     }*/
 
-    let queue: HashMap<String, JobEntry> = HashMap::new();
+    let max_builds = std::env::var("KIISRV_MAX_BUILDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BUILDS);
+    println!("Running at most {} builds at once", max_builds);
+    let mut queue = JobTable::new(max_builds, Path::new(CONFIG_DIR), Path::new(BUILD_DIR));
+    queue.timeout = std::env::var("KIISRV_BUILD_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .or(Some(DEFAULT_BUILD_TIMEOUT))
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    queue.cancel_on_disconnect = std::env::var("KIISRV_CANCEL_ON_DISCONNECT").is_ok();
+    println!("Build timeout: {:?}", queue.timeout);
+    let signer = std::env::var("KIISRV_SIGNING_KEY").ok().map(|path| {
+        let signer = Signer::load(Path::new(&path)).expect("Could not load signing key");
+        println!("Signing artifacts with key {}", signer.public_key());
+        Arc::new(signer)
+    });
+    queue.signer = signer.clone();
+    let queue = Arc::new(Mutex::new(queue));
+    {
+        let queue = queue.clone();
+        thread::spawn(move || watchdog(queue));
+    }
 
     let args: &[&ToSql] = &[];
     let config_db = Connection::open(Path::new(CONFIG_DB_FILE)).unwrap();
@@ -612,28 +1597,58 @@ fn main() {
 
     let stats_db = Connection::open(Path::new(STATS_DB_FILE)).unwrap();
     stats_db.execute(STATS_DB_SCHEMA, args).unwrap();
-
-    /*println!("\nExisting builds: ");
-    let builds = get_builds("controller-050");
-    for build in builds.lines().skip(1) {
-        println!(" - {}", build);
-        queue.insert(build.to_string(), None);
+    // Databases created before the digest column was added, fails harmlessly otherwise
+    let _ = stats_db.execute("ALTER TABLE Requests ADD COLUMN digest TEXT", args);
+    // ... and before the firmware metadata columns
+    for column in [
+        "firmware_bytes INTEGER",
+        "flash_size INTEGER",
+        "firmware_version TEXT",
+        "usb_vid INTEGER",
+        "usb_pid INTEGER",
+        "bcd_device INTEGER",
+    ] {
+        let _ = stats_db.execute(&format!("ALTER TABLE Requests ADD COLUMN {}", column), args);
     }
 
-    println!("\nBuilds to purge: ");
-    old_builds("controller-050");
-    println!("");*/
+    let backend = build_backend(&config_db);
+    fs::create_dir_all(LOG_DIR).expect("Could not create log directory");
 
-    let containers = list_containers();
+    let containers = backend.environments();
     println!("\nPossible containers:");
     println!("{:#?}", containers);
 
-    let versions = version_map(config_db);
+    let versions = version_map(config_db, backend.as_ref());
     println!("\nVersions:");
     for (v, i) in versions.iter() {
         println!("{} -> {} [{}]", v, i.container, i.channel);
     }
 
+    let jobs_db = Connection::open(Path::new(JOBS_DB_FILE)).unwrap();
+    jobs_db.execute_batch(JOBS_DB_SCHEMA).unwrap();
+    // Databases created before the checksum column was added, fails harmlessly otherwise
+    let _ = jobs_db.execute("ALTER TABLE Jobs ADD COLUMN checksum TEXT", args);
+    let _ = jobs_db.execute("ALTER TABLE Jobs ADD COLUMN firmware TEXT", args);
+    let _ = jobs_db.execute("ALTER TABLE Jobs ADD COLUMN diagnostics TEXT", args);
+    restore_jobs(
+        &mut queue.lock().expect("Could not lock mutex"),
+        jobs_db,
+        &versions,
+    );
+    dispatch(&queue, &backend);
+
+    let gc = gc_policy();
+    println!("\nGC: {:?}", gc);
+    {
+        let queue = queue.clone();
+        let gc = gc.clone();
+        let interval = std::env::var("KIISRV_GC_INTERVAL")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_GC_INTERVAL);
+        thread::spawn(move || collector(queue, gc, Duration::from_secs(interval)));
+    }
+
     let (logger_before, logger_after) = Logger::new(None);
 
     let mut layout_router = Router::new();
@@ -642,8 +1657,27 @@ fn main() {
     let mut mount = Mount::new();
     //mount.mount("/layouts/", Static::new(Path::new(LAYOUT_DIR)));
     mount.mount("/layouts/", layout_router);
-    mount.mount("/tmp/", Static::new(Path::new(BUILD_DIR)));
+    mount.mount("/tmp/", download_request);
     mount.mount("/versions", versions_request);
+    mount.mount("/keyboards", keyboards_request);
+    mount.mount("/.well-known/kiisrv-signing-key", signing_key_request);
+    mount.mount("/import", import_request);
+
+    let mut job_router = Router::new();
+    job_router.get("/:id", job_request, "job");
+    job_router.post("/:id/cancel", job_cancel_request, "job_cancel");
+    job_router.get("/:id/log", job_log_request, "job_log");
+    job_router.get("/:id/events", job_events_request, "job_events");
+    mount.mount("/jobs/", job_router);
+
+    let mut group_router = Router::new();
+    group_router.get("/:id", group_request, "group");
+    mount.mount("/groups/", group_router);
+    mount.mount("/build", build_async_request);
+
+    let mut admin_router = Router::new();
+    admin_router.post("/gc", admin_gc_request, "admin_gc");
+    mount.mount("/admin/", admin_router);
     mount.mount("/", build_request);
 
     let host = std::env::var("KIISRV_HOST");
@@ -657,6 +1691,9 @@ fn main() {
 
     let mut chain = Chain::new(mount);
     chain.link_before(Write::<JobQueue>::one(queue));
+    chain.link_before(Read::<Backend>::one(backend));
+    chain.link_before(Read::<Gc>::one(gc));
+    chain.link_before(Read::<Signing>::one(signer));
     chain.link_before(Write::<StatsDatabase>::one(stats_db));
     chain.link_before(Read::<Versions>::one(versions));
     chain.link_before(Read::<bodyparser::MaxBodyLength>::one(MAX_BODY_LENGTH));
@@ -0,0 +1,193 @@
+use crate::kll::KllFile;
+
+use chrono::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Everything kiisrv adds to the zip built by the container
+#[derive(Clone)]
+pub struct PackageInfo {
+    pub keyboard: String,
+    pub variant: String,
+    pub layout: String,
+    pub container: String,
+    /// Controller release the container was built from
+    pub version: String,
+    /// Firmware version reported over USB, when the release is known
+    pub bcd: Option<String>,
+    /// Name of the configuration json in the zip
+    pub config_file: String,
+    pub config: String,
+    /// Layers of the configuration, the per-half copies of split keyboards are left out
+    pub kll_files: Vec<KllFile>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// `manifest.json`, describes the build and every other file in the zip
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub keyboard: String,
+    pub variant: String,
+    pub layout: String,
+    pub container: String,
+    pub version: String,
+    pub bcd: Option<String>,
+    pub success: bool,
+    pub built: DateTime<Utc>,
+    pub files: Vec<ManifestFile>,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Files of the container's zip, in order. Missing or unreadable zips have no files.
+fn container_files(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut archive = match File::open(path).map(ZipArchive::new) {
+        Ok(Ok(archive)) => archive,
+        _ => return Vec::new(),
+    };
+
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = match archive.by_index(i) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if file.is_dir() {
+            continue;
+        }
+        let mut data = Vec::new();
+        if file.read_to_end(&mut data).is_ok() {
+            files.push((file.name().to_string(), data));
+        }
+    }
+    files
+}
+
+/// Rebuilds the artifact at `path` from the firmware the container produced, the generated
+/// kll files, the configuration and a manifest with checksums of all of them.
+/// The build log is included when the container didn't get as far as writing a zip.
+/// Returns the SHA-256 of the new zip.
+pub fn package(path: &Path, info: &PackageInfo, success: bool, log: &Path) -> io::Result<String> {
+    let mut ours: Vec<(String, Vec<u8>)> = info
+        .kll_files
+        .iter()
+        .map(|file| {
+            (
+                format!("kll/{}", file.name),
+                file.content.clone().into_bytes(),
+            )
+        })
+        .collect();
+    ours.push((info.config_file.clone(), info.config.clone().into_bytes()));
+
+    let mut files = container_files(path);
+    if files.is_empty() {
+        if let Ok(log) = fs::read(log) {
+            files.push(("log/build.log".to_string(), log));
+        }
+    }
+    // Our copies of the kll files and configuration replace the ones the container zipped up,
+    //  the container's copies of the kll files at the root of the zip are dropped
+    files.retain(|(name, _)| {
+        name != MANIFEST_FILE
+            && !ours.iter().any(|(n, _)| n == name)
+            && !info.kll_files.iter().any(|f| &f.name == name)
+    });
+    files.extend(ours);
+
+    let manifest = Manifest {
+        keyboard: info.keyboard.clone(),
+        variant: info.variant.clone(),
+        layout: info.layout.clone(),
+        container: info.container.clone(),
+        version: info.version.clone(),
+        bcd: info.bcd.clone(),
+        success,
+        built: Utc::now(),
+        files: files
+            .iter()
+            .map(|(name, data)| ManifestFile {
+                name: name.clone(),
+                bytes: data.len() as u64,
+                sha256: sha256_hex(data),
+            })
+            .collect(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).unwrap();
+    files.push((MANIFEST_FILE.to_string(), manifest));
+
+    // Written next to the old zip and moved over it, a download never sees half a zip
+    let tmp = path.with_extension("zip.tmp");
+    {
+        let mut zip = ZipWriter::new(File::create(&tmp)?);
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, data) in files.iter() {
+            zip.start_file(name.as_str(), options).map_err(zip_error)?;
+            zip.write_all(data)?;
+        }
+        zip.finish().map_err(zip_error)?;
+    }
+    fs::rename(&tmp, path)?;
+
+    Ok(sha256_hex(&fs::read(path)?))
+}
+
+/// Reads the manifest of an artifact
+pub fn read_manifest(path: &Path) -> io::Result<Manifest> {
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+    let file = archive.by_name(MANIFEST_FILE).map_err(zip_error)?;
+    serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the manifest of an artifact and checks every file in the zip against it
+pub fn check_manifest(path: &Path) -> Result<Manifest, String> {
+    let manifest = read_manifest(path).map_err(|e| format!("No manifest: {}", e))?;
+    let mut archive =
+        ZipArchive::new(File::open(path).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| e.to_string())?;
+        if file.is_dir() || file.name() == MANIFEST_FILE {
+            continue;
+        }
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        files.push((file.name().to_string(), data));
+    }
+
+    for (name, data) in files.iter() {
+        match manifest.files.iter().find(|f| &f.name == name) {
+            Some(f) if f.sha256 == sha256_hex(data) => {}
+            Some(_) => return Err(format!("Checksum mismatch for {}", name)),
+            None => return Err(format!("{} is not in the manifest", name)),
+        }
+    }
+    if let Some(f) = manifest
+        .files
+        .iter()
+        .find(|f| !files.iter().any(|(name, _)| name == &f.name))
+    {
+        return Err(format!("{} is missing from the zip", f.name));
+    }
+    Ok(manifest)
+}
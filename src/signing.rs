@@ -0,0 +1,89 @@
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer as _, Verifier as _};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Detached signatures are written next to the zip with this extension added
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+}
+
+pub fn from_hex(s: &str) -> Result<Vec<u8>, String> {
+    let s = s.trim();
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err("Invalid hex string".to_string());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// `MD1-Standard-<hash>.zip` -> `MD1-Standard-<hash>.zip.sig`
+pub fn signature_file(artifact: &Path) -> PathBuf {
+    let mut name = artifact.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIGNATURE_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Signs the SHA-256 digest of each artifact.
+/// The signature is stored as hex, as is the published public key.
+pub struct Signer {
+    keypair: Keypair,
+}
+
+impl Signer {
+    /// Loads an Ed25519 secret key, either the 32 raw bytes or 64 hex characters
+    pub fn load(path: &Path) -> Result<Signer, String> {
+        let contents =
+            fs::read(path).map_err(|e| format!("Could not read signing key {:?}: {}", path, e))?;
+        let bytes = match contents.len() {
+            32 => contents,
+            _ => from_hex(&String::from_utf8_lossy(&contents))?,
+        };
+        let secret = SecretKey::from_bytes(&bytes).map_err(|e| e.to_string())?;
+        let public = PublicKey::from(&secret);
+        Ok(Signer {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    pub fn public_key(&self) -> String {
+        to_hex(self.keypair.public.as_bytes())
+    }
+
+    pub fn sign(&self, data: &[u8]) -> String {
+        let digest = Sha256::digest(data);
+        to_hex(&self.keypair.sign(&digest).to_bytes())
+    }
+
+    /// Writes the detached signature of the zip, returns its path
+    pub fn sign_file(&self, artifact: &Path) -> io::Result<PathBuf> {
+        let signature = self.sign(&fs::read(artifact)?);
+        let path = signature_file(artifact);
+        fs::write(&path, format!("{}\n", signature))?;
+        Ok(path)
+    }
+}
+
+/// Checks a signature made by `Signer::sign`, both the key and signature are hex
+pub fn verify(data: &[u8], signature: &str, public_key: &str) -> Result<(), String> {
+    let public_key = PublicKey::from_bytes(&from_hex(public_key)?)
+        .map_err(|e| format!("Invalid public key: {}", e))?;
+    let signature = Signature::from_bytes(&from_hex(signature)?)
+        .map_err(|e| format!("Invalid signature: {}", e))?;
+    let digest = Sha256::digest(data);
+    public_key
+        .verify(&digest, &signature)
+        .map_err(|_| "Signature does not match".to_string())
+}